@@ -1,47 +1,168 @@
 extern crate boat_lang_core;
 
 use crate::boat_lang_core::{
-    program_parser,
-    program_translator,
-    program_optimizer,
+    compiler,
+    linker,
+    peephole_optimizer,
     interpreter,
     boat_instructions,
+    instruction_parser,
+    screen::{DisplayObserver, Screen},
+    pin_bus::{StdioBus, ThreadedLines},
+    clock::{Clock, RealClock, SimulatedClock},
+    source_map::SourceMap,
+    verifier,
+    boat_instructions::{BoatIns, Target},
 };
 
-use std::{collections::HashSet, io};
+use std::{collections::HashSet, fs, io, path::{Path, PathBuf}};
 
+/// Prints the display as ASCII art on every change
+struct AsciiDisplay;
 
-fn main() {
-    use std::fs;
+impl DisplayObserver for AsciiDisplay {
+    fn update(&mut self, screen: &Screen) {
+        println!("{screen}\n");
+    }
+}
+
+mod flags {
     use std::path::PathBuf;
 
-    let flags = xflags::parse_or_exit! {
-        /// Interpret compiled code
-        optional -i,--interpret
-        /// Print prettified compiled code
-        optional -p,--preety
-        /// Use debug mode in interpreter
-        optional -d,--debug
-        /// File or directory to parse
-        required path: PathBuf
-    };
-    let contents = fs::read_to_string(flags.path.to_str().expect("Unable to read the file")).expect("Unable to read the file");
-    let mut program = match program_parser::parse_program(&contents) {
-        Ok(program) => program,
-        Err(e) => {
-            println!("{}", e);
-            return;
+    xflags::xflags! {
+        cmd cli {
+            /// Compile a program, print the compiled code and optionally interpret it
+            default cmd run {
+                /// Interpret compiled code
+                optional -i,--interpret
+                /// Print prettified compiled code
+                optional -p,--preety
+                /// Use debug mode in interpreter
+                optional -d,--debug
+                /// Read already compiled instructions instead of a program
+                optional -c,--compiled
+                /// Advance virtual time instead of waiting in the interpreter
+                optional -s,--simulate-time
+                /// Run the peephole optimizer on compiled code
+                optional -O,--optimize
+                /// Compile for a target with `%`, `~/` and `**` commands
+                optional -x,--extended-math
+                /// Source map file, written when compiling and read with --compiled
+                optional -m,--source-map map: PathBuf
+                /// File or directory to parse
+                required path: PathBuf
+            }
+            /// Check stack balance, jump targets and argument counts of compiled code
+            cmd verify {
+                /// Read already compiled instructions instead of a program
+                optional -c,--compiled
+                /// Run the peephole optimizer on compiled code
+                optional -O,--optimize
+                /// Compile for a target with `%`, `~/` and `**` commands
+                optional -x,--extended-math
+                /// File to check
+                required path: PathBuf
+            }
         }
-    };
-    program_optimizer::optimize_reassigns(&mut program);
+    }
+}
+
+/// What the loaded file contains
+#[derive(PartialEq)]
+enum Input {
+    Program,
+    Compiled,
+    CompiledUnchecked, // Arguments of commands are left to the verifier
+}
+
+/// Compiles the program or parses compiled instructions, printing errors
+fn load(path: &Path, input: Input, optimize: bool, target: &Target, source_map_path: Option<&PathBuf>, labeled_lines: &mut HashSet<u32>, source_map: &mut SourceMap) -> Option<Vec<BoatIns>> {
+    let contents = fs::read_to_string(path.to_str().expect("Unable to read the file")).expect("Unable to read the file");
+    if input != Input::Program {
+        if let Some(map) = source_map_path {
+            let map = fs::read_to_string(map).expect("Unable to read the source map");
+            *source_map = match map.parse() {
+                Ok(source_map) => source_map,
+                Err(e) => {
+                    println!("{}", e);
+                    return None;
+                }
+            };
+        }
+        let parsed = if input == Input::Compiled {
+            instruction_parser::parse_instructions(&contents, labeled_lines)
+        } else {
+            instruction_parser::parse_instructions_unchecked(&contents, labeled_lines)
+        };
+        let translated = match parsed {
+            Ok(translated) => translated,
+            Err(e) => {
+                println!("{}", e);
+                return None;
+            }
+        };
+        if optimize {
+            let mut code = linker::unlink(translated, source_map);
+            peephole_optimizer::optimize(&mut code);
+            labeled_lines.clear();
+            *source_map = SourceMap::new();
+            Some(linker::link(code, labeled_lines, source_map))
+        } else {
+            Some(translated)
+        }
+    } else {
+        let translated = match compiler::compile(&contents, target, optimize, labeled_lines, source_map) {
+            Ok(translated) => translated,
+            Err(e) => {
+                println!("{e}");
+                return None;
+            }
+        };
+        if let Some(map) = source_map_path {
+            fs::write(map, source_map.to_string()).expect("Unable to write the source map");
+        }
+        Some(translated)
+    }
+}
+
+fn run(flags: flags::Run) {
     let mut labeled_lines = HashSet::<u32>::new();
-    let translated = program_translator::translate_program(program, &mut labeled_lines);
+    let mut source_map = SourceMap::new();
+    let Some(translated) = load(&flags.path, if flags.compiled { Input::Compiled } else { Input::Program }, flags.optimize, &Target { extended_math: flags.extended_math }, flags.source_map.as_ref(), &mut labeled_lines, &mut source_map) else {
+        return;
+    };
     if flags.interpret {
-        let out = io::stdout();
-        let inp = io::stdin().lock();
-        interpreter::interpret(&translated, out, inp, flags.debug);
+        let mut bus = StdioBus::new(ThreadedLines::spawn(io::BufReader::new(io::stdin())), io::stdout());
+        let mut trace = io::stdout();
+        let trace = flags.debug.then_some(&mut trace as &mut dyn io::Write);
+        let mut clock: Box<dyn Clock> = if flags.simulate_time { Box::new(SimulatedClock::new()) } else { Box::new(RealClock::new()) };
+        if let Err(e) = interpreter::interpret(&translated, &mut bus, &mut AsciiDisplay, clock.as_mut(), Some(&source_map), trace) {
+            eprintln!("{e}");
+        }
     }
     let text = boat_instructions::translated_to_string2(translated, flags.preety, &labeled_lines);
     println!("{}", text);
 }
 
+fn verify(flags: flags::Verify) {
+    // The verifier reports all wrong argument counts instead of the parser stopping at the first
+    let Some(translated) = load(&flags.path, if flags.compiled { Input::CompiledUnchecked } else { Input::Program }, flags.optimize, &Target { extended_math: flags.extended_math }, None, &mut HashSet::new(), &mut SourceMap::new()) else {
+        std::process::exit(1);
+    };
+    match verifier::verify(&translated) {
+        Ok(()) => println!("ok"),
+        Err(errors) => {
+            for error in errors {
+                println!("error: {error}");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    match flags::Cli::from_env_or_exit().subcommand {
+        flags::CliCmd::Run(flags) => run(flags),
+        flags::CliCmd::Verify(flags) => verify(flags),
+    }
+}
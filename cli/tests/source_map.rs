@@ -0,0 +1,38 @@
+use std::{fs, process::Command};
+
+const PROGRAM: &str = "print = out(1);
+{
+    noinline function half(x) {
+        return x / 2;
+    }
+    let a = \"x\";
+    if (a == \"x\") {
+        print(half(4));
+    }
+    print(a - 1);
+}
+";
+
+// Source map written with `-m` while compiling points runtime errors of the compiled code back to the program,
+// also after the peephole optimizer moves instructions
+#[test]
+fn source_map_round_trip() {
+    let dir = std::env::temp_dir().join(format!("boat_source_map_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (program, map, compiled) = (dir.join("program.boat"), dir.join("program.map"), dir.join("program.txt"));
+    fs::write(&program, PROGRAM).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cli")).arg("-m").arg(&map).arg(&program).output().unwrap();
+    assert!(output.status.success());
+    fs::write(&compiled, &output.stdout).unwrap();
+    for (optimize, index) in [(false, 14), (true, 13)] {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_cli"));
+        command.args(["-c", "-i", "-s"]).arg("-m").arg(&map).arg(&compiled);
+        if optimize {
+            command.arg("-O");
+        }
+        let stderr = String::from_utf8(command.output().unwrap().stderr).unwrap();
+        let expected = format!("runtime error at {index}| - $a 1 (line 10:11): `x` is not a number");
+        assert_eq!(stderr.lines().next(), Some(expected.as_str()), "{stderr}");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
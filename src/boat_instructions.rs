@@ -1,11 +1,11 @@
-use std::{collections::HashSet, fmt::Display};
+use std::{collections::HashSet, fmt::Display, str::FromStr};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BoatCmd {
     Push,         // Push value on top of stack
     Goto,         // Go to instruction with index
     Input,        // Block until input value from pin and push it on top of stack
-    InputAsync,   // Block until input value from pin or timeout at arg2 in seconds and push it on top of stack
+    InputAsync,   // Block until input value from pin or timeout at arg2 in seconds and push it (or empty string on timeout) on top of stack
     Output,       // Output to pin at pos 1 value at pos 2
     Add,          // Push sum of two values to stack
     Sub,          // Push difference of two values to stack
@@ -24,49 +24,190 @@ pub enum BoatCmd {
     DisplayClear, // Clear 7x7 display
     Store,        // Store value at 2 argument into memory at 1 argument(s - stack; kv - kvs)
     Clear,        // Clear memory at 1 argument(s - stack; kv - kvs)
+    Mod,          // Push remainder of floored division, it has the sign of the second value
+    IntDiv,       // Push quotient of two values rounded down
+    Pow,          // Push the first value raised to the power of the second
 }
 
+pub type Label = u32;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BoatArg {
     FromStack,
     Const(String),
     FromKVS(String),
+    Label(Label), // Index of the labeled instruction, resolved by `linker::link`
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BoatIns {
     pub cmd: BoatCmd,
     pub args: Vec<BoatArg>,
 }
 
-impl Display for BoatCmd {
+/// Key set by `kr ~ $` to pop an unused value, `~` is not a valid variable name
+pub const DISCARD_KEY: &str = "~";
+
+/// How an argument of a command may be given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    Any,      // Constant, `$key` or `$`
+    NotStack, // Constant or `$key`, so stack effects are known without running the program
+}
+
+/// Static description of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmdInfo {
+    pub cmd: BoatCmd,
+    pub mnemonic: &'static str,
+    pub args: &'static [ArgKind],
+    pub pushes: usize,        // Values pushed after the `$` arguments are popped
+    pub jump: Option<usize>,  // Argument holding the jump target
+    pub extended_math: bool,  // Only available on targets with extended math
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ArgError {
+    Count { expected: usize, found: usize },
+    Stack(usize), // 1-based argument given as `$` where it is not allowed
+}
+
+impl Display for ArgError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use BoatCmd::*;
         match self {
-            Push => write!(f, "p"),
-            Goto => write!(f, "g"),
-            Input => write!(f, "i"),
-            InputAsync => write!(f, "ia"),
-            Output => write!(f, "o"),
-            Add => write!(f, "+"),
-            Sub => write!(f, "-"),
-            Mul => write!(f, "*"),
-            Div => write!(f, "/"),
-            Conc => write!(f, ".."),
-            KVSet => write!(f, "ka"),
-            KVDel => write!(f, "kd"),
-            KVReSet => write!(f, "kr"),
-            Cmp => write!(f, "c"),
-            Eq => write!(f, "="),
-            Lt => write!(f, "<"),
-            Gt => write!(f, ">"),
-            Sleep => write!(f, "s"),
-            Display => write!(f, "di"),
-            DisplayClear => write!(f, "dc"),
-            Store => write!(f, "st"),
-            Clear => write!(f, "clr"),
+            ArgError::Count { expected, found } => write!(f, "takes {expected} arguments but {found} were given"),
+            ArgError::Stack(n) => write!(f, "argument {n} can not be `$`"),
+        }
+    }
+}
+
+const fn info(cmd: BoatCmd, mnemonic: &'static str, args: &'static [ArgKind], pushes: usize, jump: Option<usize>) -> CmdInfo {
+    CmdInfo { cmd, mnemonic, args, pushes, jump, extended_math: false }
+}
+
+const fn extended_math(info: CmdInfo) -> CmdInfo {
+    CmdInfo { extended_math: true, ..info }
+}
+
+use ArgKind::{Any, NotStack};
+
+/// Every command in the order of `BoatCmd` variants.
+/// `st s` and `clr s` replace the whole stack, which is not counted in `pushes`
+pub const INSTRUCTION_SET: [CmdInfo; 25] = [
+    info(BoatCmd::Push, "p", &[Any], 1, None),
+    info(BoatCmd::Goto, "g", &[Any], 0, Some(0)),
+    info(BoatCmd::Input, "i", &[Any], 1, None),
+    info(BoatCmd::InputAsync, "ia", &[Any, Any], 1, None),
+    info(BoatCmd::Output, "o", &[Any, Any], 0, None),
+    info(BoatCmd::Add, "+", &[Any, Any], 1, None),
+    info(BoatCmd::Sub, "-", &[Any, Any], 1, None),
+    info(BoatCmd::Mul, "*", &[Any, Any], 1, None),
+    info(BoatCmd::Div, "/", &[Any, Any], 1, None),
+    info(BoatCmd::Conc, "..", &[Any, Any], 1, None),
+    info(BoatCmd::KVReSet, "kr", &[Any, Any], 0, None),
+    info(BoatCmd::KVSet, "ka", &[Any, Any], 0, None),
+    info(BoatCmd::KVDel, "kd", &[Any], 0, None),
+    info(BoatCmd::Cmp, "c", &[Any, Any], 0, Some(1)),
+    info(BoatCmd::Lt, "<", &[Any, Any], 1, None),
+    info(BoatCmd::Eq, "=", &[Any, Any], 1, None),
+    info(BoatCmd::Gt, ">", &[Any, Any], 1, None),
+    info(BoatCmd::Sleep, "s", &[Any], 0, None),
+    info(BoatCmd::Display, "di", &[Any, Any], 0, None),
+    info(BoatCmd::DisplayClear, "dc", &[], 0, None),
+    info(BoatCmd::Store, "st", &[NotStack, Any], 0, None),
+    info(BoatCmd::Clear, "clr", &[NotStack], 0, None),
+    extended_math(info(BoatCmd::Mod, "%", &[Any, Any], 1, None)),
+    extended_math(info(BoatCmd::IntDiv, "~/", &[Any, Any], 1, None)),
+    extended_math(info(BoatCmd::Pow, "**", &[Any, Any], 1, None)),
+];
+
+// Entries are looked up by variant index
+const _: () = {
+    let mut i = 0;
+    while i < INSTRUCTION_SET.len() {
+        assert!(INSTRUCTION_SET[i].cmd as usize == i, "INSTRUCTION_SET is in the order of BoatCmd");
+        i += 1;
+    }
+};
+
+/// Capabilities of the machine the program is compiled for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Target {
+    pub extended_math: bool, // `%`, `~/` and `**` commands
+}
+
+impl Target {
+    pub fn supports(&self, cmd: BoatCmd) -> bool {
+        !cmd.info().extended_math || self.extended_math
+    }
+}
+
+impl BoatCmd {
+    pub fn info(self) -> &'static CmdInfo {
+        &INSTRUCTION_SET[self as usize]
+    }
+
+    pub fn arity(self) -> usize {
+        self.info().args.len()
+    }
+
+    pub fn pushes(self) -> usize {
+        self.info().pushes
+    }
+}
+
+impl BoatIns {
+    pub fn pops(&self) -> usize {
+        self.args.iter().filter(|arg| **arg == BoatArg::FromStack).count()
+    }
+
+    /// Change of the stack size after the instruction
+    pub fn stack_effect(&self) -> isize {
+        self.cmd.pushes() as isize - self.pops() as isize
+    }
+
+    /// Jump target argument of `g` and `c`
+    pub fn target(&self) -> Option<&BoatArg> {
+        self.args.get(self.cmd.info().jump?)
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut BoatArg> {
+        self.args.get_mut(self.cmd.info().jump?)
+    }
+
+    /// Checks the number of arguments and that `$` is only given where the command allows it
+    pub fn check_args(&self) -> Result<(), ArgError> {
+        let expected = self.cmd.info().args;
+        if self.args.len() != expected.len() {
+            return Err(ArgError::Count { expected: expected.len(), found: self.args.len() });
+        }
+        match self.args.iter().zip(expected).position(|(arg, kind)| *arg == BoatArg::FromStack && *kind == ArgKind::NotStack) {
+            Some(n) => Err(ArgError::Stack(n + 1)),
+            None => Ok(()),
         }
     }
+
+    pub fn discard() -> Self {
+        BoatIns { cmd: BoatCmd::KVReSet, args: vec![BoatArg::Const(DISCARD_KEY.to_owned()), BoatArg::FromStack] }
+    }
+}
+
+impl Display for BoatCmd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.info().mnemonic)
+    }
+}
+
+impl FromStr for BoatCmd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        INSTRUCTION_SET
+            .iter()
+            .find(|info| info.mnemonic == s)
+            .map(|info| info.cmd)
+            .ok_or_else(|| format!("unknown command `{s}`"))
+    }
 }
 
 impl Display for BoatArg {
@@ -75,6 +216,17 @@ impl Display for BoatArg {
             BoatArg::FromStack => write!(f, "$"),
             BoatArg::Const(s) => write!(f, "{s}"),
             BoatArg::FromKVS(s) => write!(f, "${s}"),
+            BoatArg::Label(l) => write!(f, "@{l}"),
+        }
+    }
+}
+
+impl From<&str> for BoatArg {
+    fn from(s: &str) -> Self {
+        match s.strip_prefix('$') {
+            Some("") => BoatArg::FromStack,
+            Some(key) => BoatArg::FromKVS(key.to_owned()),
+            None => BoatArg::Const(s.to_owned()),
         }
     }
 }
@@ -1,56 +1,160 @@
-use crate::boat_instructions::{BoatArg, BoatIns};
-use std::collections::HashMap;
+use crate::boat_instructions::{BoatArg, BoatIns, Label};
+use std::{collections::HashMap, fmt::Display, ops::RangeInclusive};
+
+/// Location in the program source. `start` and `end` are byte offsets, `line` and `col` start at 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    /// Span from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        let (line, col) = span.start_pos().line_col();
+        Span { start: span.start(), end: span.end(), line, col }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum BoatExpr {
-    Value(String),
-    Var(String),
+    Value(String, Span),
+    Var(String, Span),
     Function {
         name: String,
         args: Vec<BoatExpr>,
+        span: Span,
     },
     BinOp {
         lhs: Box<BoatExpr>,
         op: BoatOp,
         rhs: Box<BoatExpr>,
+        span: Span,
     },
 }
 
+impl BoatExpr {
+    pub fn span(&self) -> Span {
+        match self {
+            BoatExpr::Value(_, span) | BoatExpr::Var(_, span) => *span,
+            BoatExpr::Function { span, .. } | BoatExpr::BinOp { span, .. } => *span,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BoatOp {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    IntDiv,
+    Pow, // Unrolled into multiplications for small constant exponents without extended math
     Conc,
     Lt,
     Gt,
     Eq,
+    Ne, // Translated as `=` of the operands and `= $ 0`
+    Le, // Translated as negated `>`
+    Ge, // Translated as negated `<`
+    And, // 1 if both operands are non-zero, the right one is only evaluated if the left one is non-zero
+    Or,  // 1 if any operand is non-zero, the right one is only evaluated if the left one is zero
+    Not, // Prefix `!`, 1 if the right operand is zero. The left one is constant 0, like in `-x`
+}
+
+/// Inlining of calls to the function, set with `inline function` or `noinline function`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InlineHint {
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
 #[derive(Debug)]
 pub enum Statement {
-    If { expr: BoatExpr, block: Block, else_block: Option<Block> },
-    While { expr: BoatExpr, block: Block },
-    Assign { var_name: String, expr: BoatExpr },
-    Reassign { var_name: String, expr: BoatExpr },
-    FunctionDefinition { name: String, arg_names: Vec<String>, block: Block },
+    If { expr: BoatExpr, block: Block, else_block: Option<Block>, span: Span },
+    While { expr: BoatExpr, block: Block, span: Span },
+    // `init` and `step` have at most one statement, variables declared in `init` live until the loop ends
+    For { init: Block, expr: BoatExpr, step: Block, block: Block, span: Span },
+    Assign { var_name: String, expr: BoatExpr, span: Span }, // `let`, declares the variable until the end of the block
+    Reassign { var_name: String, expr: BoatExpr, span: Span },
+    FunctionDefinition { name: String, arg_names: Vec<String>, block: Block, inline: InlineHint, span: Span },
     Expr(BoatExpr),
-    Return(BoatExpr),
+    Return(BoatExpr, Span),
+    Break(Span),
+    Continue(Span),
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::If { span, .. }
+            | Statement::While { span, .. }
+            | Statement::For { span, .. }
+            | Statement::Assign { span, .. }
+            | Statement::Reassign { span, .. }
+            | Statement::FunctionDefinition { span, .. }
+            | Statement::Return(_, span)
+            | Statement::Break(span)
+            | Statement::Continue(span) => *span,
+            Statement::Expr(expr) => expr.span(),
+        }
+    }
 }
 
 pub type Block = Vec<Statement>;
 
+/// Whether the block has a `return`, not looking into function definitions
+pub fn has_return(block: &Block) -> bool {
+    block.iter().any(|s| match s {
+        Statement::Return(..) => true,
+        Statement::If { block, else_block, .. } => has_return(block) || else_block.as_ref().is_some_and(has_return),
+        Statement::While { block, .. } | Statement::For { block, .. } => has_return(block),
+        _ => false,
+    })
+}
+
 pub enum Function {
     InProgram {
-        begin_pos: u32,
+        begin: Label,
         arg_names: Vec<String>,
+        returns_value: bool, // Body has a `return`
     },
     Predefined {
+        arity: RangeInclusive<usize>,
         translator: Box<dyn Fn(Vec<BoatArg>) -> Vec<BoatIns>>
     }
 }
 
+impl Function {
+    /// Whether a call leaves a value on the stack
+    pub fn returns_value(&self) -> bool {
+        match self {
+            Function::InProgram { returns_value, .. } => *returns_value,
+            Function::Predefined { arity, translator } => {
+                let instructions = translator(vec![BoatArg::Const("0".to_owned()); *arity.start()]);
+                instructions.iter().map(BoatIns::stack_effect).sum::<isize>() > 0
+            }
+        }
+    }
+}
+
 pub type Functions = HashMap<String, Function>;
 
 pub struct Program {
@@ -0,0 +1,67 @@
+use std::{thread, time::{Duration, Instant}};
+
+/// Time source of the interpreter, used by `s` and `ia` timeouts
+pub trait Clock {
+    /// Time passed since the program start
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+    /// Time advances only on sleep, so it can not measure waiting for events outside of the program
+    fn is_simulated(&self) -> bool;
+}
+
+/// Wall clock, sleeping for real
+pub struct RealClock {
+    start: Instant,
+}
+
+impl RealClock {
+    pub fn new() -> Self {
+        RealClock { start: Instant::now() }
+    }
+}
+
+impl Default for RealClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn is_simulated(&self) -> bool {
+        false
+    }
+}
+
+/// Virtual clock advancing instantly on sleep
+#[derive(Debug, Default)]
+pub struct SimulatedClock {
+    now: Duration,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Duration {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+
+    fn is_simulated(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,49 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::boat_instructions::{BoatIns, Target};
+use crate::program_checker::{self, Diagnostic};
+use crate::program_parser::{self, Rule};
+use crate::source_map::SourceMap;
+use crate::{linker, peephole_optimizer, program_optimizer, program_translator};
+
+#[derive(Debug)]
+pub enum CompileError {
+    Parse(Box<pest::error::Error<Rule>>),
+    Check(Vec<Diagnostic>),
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Parse(e) => write!(f, "{e}"),
+            CompileError::Check(diagnostics) => {
+                let lines = diagnostics.iter().map(|diagnostic| format!("error: {diagnostic}")).collect::<Vec<String>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Parses, checks and translates the program for the target and links it.
+/// Source-level optimizations always run, `optimize` adds the peephole optimizer.
+/// Referenced indexes are collected into `labeled_lines` and instruction spans into `source_map`
+pub fn compile(
+    contents: &str,
+    target: &Target,
+    optimize: bool,
+    labeled_lines: &mut HashSet<u32>,
+    source_map: &mut SourceMap,
+) -> Result<Vec<BoatIns>, CompileError> {
+    let mut program = program_parser::parse_program(contents).map_err(CompileError::Parse)?;
+    program_checker::check_program(&program, target).map_err(CompileError::Check)?;
+    program_optimizer::inline_functions(&mut program);
+    program_optimizer::optimize_exprs(&mut program);
+    program_optimizer::eliminate_dead_code(&mut program);
+    let mut code = program_translator::translate_program(program, target);
+    if optimize {
+        peephole_optimizer::optimize(&mut code);
+    }
+    Ok(linker::link(code, labeled_lines, source_map))
+}
@@ -0,0 +1,142 @@
+use std::cmp::Ordering;
+
+use crate::boat_program::{BoatExpr, BoatOp};
+use crate::interpreter::floored_mod;
+
+fn parse_num(s: &str) -> Option<f32> {
+    s.parse::<f32>().ok()
+}
+
+// Whether `c` would fall through for the value, it does not trim numbers
+fn truthy(s: &str) -> Option<bool> {
+    parse_num(s).map(|value| value != 0.)
+}
+
+fn bool_value(value: bool) -> String {
+    (value as usize as f32).to_string()
+}
+
+// Result of the instruction for constant operands, as computed by the interpreter
+fn fold(lhs: &str, op: &BoatOp, rhs: &str) -> Option<String> {
+    let numeric = |f: fn(f32, f32) -> f32| Some(f(parse_num(lhs.trim())?, parse_num(rhs.trim())?).to_string());
+    let compare = |f: fn(&f32, &f32) -> bool| Some(bool_value(f(&parse_num(lhs)?, &parse_num(rhs)?)));
+    match op {
+        BoatOp::Add => numeric(|a, b| a + b),
+        BoatOp::Sub => numeric(|a, b| a - b),
+        BoatOp::Mul => numeric(|a, b| a * b),
+        BoatOp::Div => numeric(|a, b| a / b),
+        BoatOp::Mod => numeric(floored_mod),
+        BoatOp::IntDiv => numeric(|a, b| (a / b).floor()),
+        BoatOp::Pow => numeric(f32::powf),
+        BoatOp::Conc => Some(format!("{lhs}{rhs}")),
+        BoatOp::Eq => Some(bool_value(lhs == rhs)),
+        BoatOp::Gt => compare(f32::gt),
+        BoatOp::Lt => compare(f32::lt),
+        // Negations of `>` and `<` are true for NaN, unlike `<=` and `>=`
+        BoatOp::Le => compare(|a, b| a.partial_cmp(b) != Some(Ordering::Greater)),
+        BoatOp::Ge => compare(|a, b| a.partial_cmp(b) != Some(Ordering::Less)),
+        BoatOp::Ne => Some(bool_value(lhs != rhs)),
+        BoatOp::And => Some(bool_value(truthy(lhs)? && truthy(rhs)?)),
+        BoatOp::Or => Some(bool_value(truthy(lhs)? || truthy(rhs)?)),
+        BoatOp::Not => Some(bool_value(!truthy(rhs)?)),
+    }
+}
+
+fn is_num(expr: &BoatExpr, value: f32) -> bool {
+    matches!(expr, BoatExpr::Value(v, _) if parse_num(v.trim()) == Some(value))
+}
+
+// Value is already in the form numeric instructions give their results in, like `7` and not `07`
+fn is_canonical_number(expr: &BoatExpr) -> bool {
+    match expr {
+        BoatExpr::Value(v, _) => parse_num(v).is_some_and(|value| value.to_string() == *v),
+        BoatExpr::BinOp { op, .. } => !matches!(op, BoatOp::Conc),
+        _ => false,
+    }
+}
+
+fn is_empty_string(expr: &BoatExpr) -> bool {
+    matches!(expr, BoatExpr::Value(v, _) if v.is_empty())
+}
+
+/// Folds operations on constants and drops identity operations like `x * 1`, `x + 0` and `x .. ""`.
+/// `&&` and `||` are folded when the constant left operand decides the result.
+/// Numeric identities are only dropped when the other operand is already a canonical number,
+/// as the operation turns values like `07` into `7`, which `==` compares as strings
+pub fn optimize_expr(expr: BoatExpr) -> BoatExpr {
+    match expr {
+        BoatExpr::Function { name, args, span } => {
+            BoatExpr::Function { name, args: args.into_iter().map(optimize_expr).collect(), span }
+        }
+        BoatExpr::BinOp { lhs, op, rhs, span } => {
+            let lhs = optimize_expr(*lhs);
+            let rhs = optimize_expr(*rhs);
+            if let (BoatExpr::Value(l, _), BoatExpr::Value(r, _)) = (&lhs, &rhs) {
+                return match fold(l, &op, r) {
+                    Some(value) => BoatExpr::Value(value, span),
+                    // Left for the interpreter to report
+                    None => BoatExpr::BinOp { lhs: Box::new(lhs), op, rhs: Box::new(rhs), span },
+                };
+            }
+            let decided = |value: bool| matches!(&lhs, BoatExpr::Value(v, _) if truthy(v) == Some(value));
+            match op {
+                // Right operand is not evaluated
+                BoatOp::And if decided(false) => BoatExpr::Value(bool_value(false), span),
+                BoatOp::Or if decided(true) => BoatExpr::Value(bool_value(true), span),
+                BoatOp::Add if is_num(&lhs, 0.) && is_canonical_number(&rhs) => rhs,
+                BoatOp::Add | BoatOp::Sub if is_num(&rhs, 0.) && is_canonical_number(&lhs) => lhs,
+                BoatOp::Mul if is_num(&lhs, 1.) && is_canonical_number(&rhs) => rhs,
+                BoatOp::Mul | BoatOp::Div | BoatOp::Pow if is_num(&rhs, 1.) && is_canonical_number(&lhs) => lhs,
+                BoatOp::Conc if is_empty_string(&lhs) => rhs,
+                BoatOp::Conc if is_empty_string(&rhs) => lhs,
+                _ => BoatExpr::BinOp { lhs: Box::new(lhs), op, rhs: Box::new(rhs), span },
+            }
+        }
+        value_or_var => value_or_var,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::boat_instructions::{BoatArg, BoatCmd, BoatIns};
+    use crate::clock::SimulatedClock;
+    use crate::interpreter::interpret;
+    use crate::pin_bus::{RecordingBus, ScriptedBus};
+
+    // Result of the command on constant operands, `None` when it fails
+    fn execute(cmd: BoatCmd, lhs: &str, rhs: &str) -> Option<String> {
+        let program = [
+            BoatIns { cmd, args: vec![BoatArg::Const(lhs.into()), BoatArg::Const(rhs.into())] },
+            BoatIns { cmd: BoatCmd::Output, args: vec![BoatArg::Const("1".into()), BoatArg::FromStack] },
+        ];
+        let mut bus = RecordingBus::new(ScriptedBus::new());
+        interpret(&program, &mut bus, &mut (), &mut SimulatedClock::new(), None, None).ok()?;
+        Some(bus.outputs("1")[0].1.to_owned())
+    }
+
+    #[test]
+    fn fold_matches_interpreter() {
+        let ops = [BoatOp::Add, BoatOp::Sub, BoatOp::Mul, BoatOp::Div, BoatOp::Mod, BoatOp::IntDiv, BoatOp::Pow, BoatOp::Conc, BoatOp::Gt, BoatOp::Lt, BoatOp::Eq];
+        let values = ["-7", "3", "7", "-3", "2", "0", "0.5", "07", " 5", "abc", ""];
+        let mut checked = HashSet::new();
+        for (i, op) in ops.iter().enumerate() {
+            for lhs in values {
+                for rhs in values {
+                    let folded = fold(lhs, op, rhs);
+                    // Operations the interpreter fails on are left unfolded
+                    if let Some(folded) = &folded {
+                        checked.insert(i);
+                        assert_eq!(Some(folded.clone()), execute(op.clone().into(), lhs, rhs), "{lhs:?} {op:?} {rhs:?}");
+                    }
+                }
+            }
+        }
+        assert_eq!(checked.len(), ops.len());
+        assert_eq!(fold("-7", &BoatOp::Mod, "3").as_deref(), Some("2"));
+        assert_eq!(fold("7", &BoatOp::Mod, "-3").as_deref(), Some("-2"));
+        assert_eq!(fold("-7", &BoatOp::IntDiv, "2").as_deref(), Some("-4"));
+    }
+}
@@ -1,7 +1,7 @@
 use pest::pratt_parser::PrattParser;
 use pest::iterators::Pairs;
 use crate::program_parser::Rule;
-use crate::boat_program::{BoatExpr, BoatOp};
+use crate::boat_program::{BoatExpr, BoatOp, Span};
 
 lazy_static::lazy_static! {
     static ref BOAT_EXPR_PARSER: PrattParser<Rule> = {
@@ -11,29 +11,34 @@ lazy_static::lazy_static! {
         // Precedence is defined lowest to highest
         PrattParser::new()
             // Addition and subtract have equal precedence
-            .op(Op::infix(land, Left) | Op::infix(lor, Left))
-            .op(Op::infix(gt, Left) | Op::infix(lt, Left) | Op::infix(eq, Left))
+            .op(Op::infix(lor, Left))
+            .op(Op::infix(land, Left))
+            .op(Op::infix(gt, Left) | Op::infix(lt, Left) | Op::infix(eq, Left) | Op::infix(ne, Left) | Op::infix(ge, Left) | Op::infix(le, Left))
             .op(Op::infix(concat, Left))
             .op(Op::infix(add, Left) | Op::infix(subtract, Left))
-            .op(Op::infix(multiply, Left) | Op::infix(divide, Left))
-            .op(Op::prefix(unary_minus))
+            .op(Op::infix(multiply, Left) | Op::infix(divide, Left) | Op::infix(int_divide, Left) | Op::infix(modulo, Left))
+            .op(Op::prefix(unary_minus) | Op::prefix(not))
+            .op(Op::infix(power, Right))
     };
 }
 
 pub fn parse_pairs(pairs: Pairs<Rule>) -> BoatExpr {
     BOAT_EXPR_PARSER
-        .map_primary(|primary| match primary.as_rule() {
-            Rule::string => BoatExpr::Value(primary.into_inner().next().unwrap().as_str().to_owned()),
-            Rule::integer => BoatExpr::Value(primary.as_str().to_owned()),
-            Rule::expr => parse_pairs(primary.into_inner()),
-            Rule::function => {
-                let mut inner = primary.into_inner();
-                let name = inner.next().unwrap().as_str().to_owned();
-                let args = inner.map(|pair| parse_pairs(pair.into_inner())).collect();
-                BoatExpr::Function { name, args }
+        .map_primary(|primary| {
+            let span = Span::from(primary.as_span());
+            match primary.as_rule() {
+                Rule::string => BoatExpr::Value(primary.into_inner().next().unwrap().as_str().to_owned(), span),
+                Rule::integer => BoatExpr::Value(primary.as_str().to_owned(), span),
+                Rule::expr => parse_pairs(primary.into_inner()),
+                Rule::function => {
+                    let mut inner = primary.into_inner();
+                    let name = inner.next().unwrap().as_str().to_owned();
+                    let args = inner.map(|pair| parse_pairs(pair.into_inner())).collect();
+                    BoatExpr::Function { name, args, span }
+                }
+                Rule::name => BoatExpr::Var(primary.as_str().to_owned(), span),
+                rule => unreachable!("Expr::parse expected atom, found {:?}", rule),
             }
-            Rule::name => BoatExpr::Var(primary.as_str().to_owned()),
-            rule => unreachable!("Expr::parse expected atom, found {:?}", rule),
         })
         .map_infix(|lhs, op, rhs| {
             let op = match op.as_rule() {
@@ -41,22 +46,35 @@ pub fn parse_pairs(pairs: Pairs<Rule>) -> BoatExpr {
                 Rule::subtract => BoatOp::Sub,
                 Rule::multiply => BoatOp::Mul,
                 Rule::divide => BoatOp::Div,
+                Rule::int_divide => BoatOp::IntDiv,
+                Rule::modulo => BoatOp::Mod,
+                Rule::power => BoatOp::Pow,
                 Rule::concat => BoatOp::Conc,
                 Rule::gt => BoatOp::Gt,
                 Rule::lt => BoatOp::Lt,
                 Rule::eq => BoatOp::Eq,
-                Rule::land => BoatOp::Mul,
-                Rule::lor => BoatOp::Add,
+                Rule::ne => BoatOp::Ne,
+                Rule::ge => BoatOp::Ge,
+                Rule::le => BoatOp::Le,
+                Rule::land => BoatOp::And,
+                Rule::lor => BoatOp::Or,
                 _ => unreachable!(),
             };
             BoatExpr::BinOp {
+                span: lhs.span().to(rhs.span()),
                 lhs: Box::new(lhs),
                 op,
                 rhs: Box::new(rhs),
             }
         })
         .map_prefix(|op, exp| {
-            BoatExpr::BinOp { lhs: Box::new(BoatExpr::Value("0".to_owned())), op: BoatOp::Sub, rhs: Box::new(exp) }
+            let op_span = Span::from(op.as_span());
+            let op = match op.as_rule() {
+                Rule::unary_minus => BoatOp::Sub,
+                Rule::not => BoatOp::Not,
+                _ => unreachable!(),
+            };
+            BoatExpr::BinOp { span: op_span.to(exp.span()), lhs: Box::new(BoatExpr::Value("0".to_owned(), op_span)), op, rhs: Box::new(exp) }
         })
         .parse(pairs)
 }
@@ -1,6 +1,6 @@
 use crate::boat_program::{BoatExpr, BoatOp, Function, Functions};
-use crate::boat_instructions::{BoatIns, BoatArg, BoatCmd};
-use std::collections::HashSet;
+use crate::boat_instructions::{BoatIns, BoatArg, BoatCmd, Label, Target};
+use crate::linker::Code;
 
 impl From<BoatOp> for BoatCmd {
     fn from(val: BoatOp) -> Self {
@@ -10,55 +10,167 @@ impl From<BoatOp> for BoatCmd {
             Sub => BoatCmd::Sub,
             Mul => BoatCmd::Mul,
             Div => BoatCmd::Div,
+            Mod => BoatCmd::Mod,
+            IntDiv => BoatCmd::IntDiv,
+            Pow => BoatCmd::Pow,
             Conc => BoatCmd::Conc,
             Gt => BoatCmd::Gt,
             Lt => BoatCmd::Lt,
             Eq => BoatCmd::Eq,
+            Ne | Le | Ge => unreachable!("comparisons are translated as negation of another one"),
+            And | Or | Not => unreachable!("logical operators are translated to jumps"),
         }
     }
 }
 
-pub fn translate_expr(arg: BoatExpr, instruction_index: &mut u32, instructions: &mut Vec<BoatIns>, functions: &Functions, labeled_lines: &mut HashSet<u32>) -> BoatArg {
+/// Largest constant exponent of `**` unrolled into multiplications on targets without extended math
+pub const MAX_UNROLLED_POWER: usize = 8;
+
+/// Exponent of `x ** n` that can be unrolled into multiplications
+pub fn unrolled_exponent(exponent: &BoatExpr) -> Option<usize> {
+    match exponent {
+        BoatExpr::Value(value, _) => value.parse::<usize>().ok().filter(|n| *n <= MAX_UNROLLED_POWER),
+        _ => None,
+    }
+}
+
+// Key holding the computed base of an unrolled power, `~` is not valid in variable names
+const POWER_KEY: &str = "~pow";
+
+fn translate_power(base: BoatExpr, exponent: usize, code: &mut Code, functions: &Functions, target: &Target) -> BoatArg {
+    let span = base.span();
+    let base = translate_expr(base, code, functions, target);
+    match exponent {
+        0 => {
+            if base == BoatArg::FromStack {
+                code.push(BoatIns::discard(), span);
+            }
+            return BoatArg::Const("1".to_owned());
+        }
+        1 => return base,
+        _ => {}
+    }
+    let stored = base == BoatArg::FromStack;
+    let base = if stored {
+        code.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const(POWER_KEY.to_owned()), BoatArg::FromStack] }, span);
+        BoatArg::FromKVS(POWER_KEY.to_owned())
+    } else {
+        base
+    };
+    code.push(BoatIns { cmd: BoatCmd::Mul, args: vec![base.clone(), base.clone()] }, span);
+    for _ in 2..exponent {
+        code.push(BoatIns { cmd: BoatCmd::Mul, args: vec![BoatArg::FromStack, base.clone()] }, span);
+    }
+    if stored {
+        code.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const(POWER_KEY.to_owned())] }, span);
+    }
+    BoatArg::FromStack
+}
+
+/// Translates the expression so that it jumps to `false_label` if the value is 0 and falls through otherwise.
+/// Right operands of `&&` and `||` are skipped when the left one decides the result
+pub fn translate_condition(expr: BoatExpr, false_label: Label, code: &mut Code, functions: &Functions, target: &Target) {
+    let span = expr.span();
+    match expr {
+        BoatExpr::BinOp { lhs, op: BoatOp::And, rhs, .. } => {
+            translate_condition(*lhs, false_label, code, functions, target);
+            translate_condition(*rhs, false_label, code, functions, target);
+        }
+        BoatExpr::BinOp { lhs, op: BoatOp::Or, rhs, .. } => {
+            let true_label = code.new_label();
+            let rhs_label = code.new_label();
+            translate_condition(*lhs, rhs_label, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(true_label)] }, span);
+            code.place(rhs_label);
+            translate_condition(*rhs, false_label, code, functions, target);
+            code.place(true_label);
+        }
+        BoatExpr::BinOp { op: BoatOp::Not, rhs, .. } => {
+            let true_label = code.new_label();
+            translate_condition(*rhs, true_label, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(false_label)] }, span);
+            code.place(true_label);
+        }
+        expr => {
+            let arg = translate_expr(expr, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::Cmp, args: vec![arg, BoatArg::Label(false_label)] }, span);
+        }
+    }
+}
+
+pub fn translate_expr(arg: BoatExpr, code: &mut Code, functions: &Functions, target: &Target) -> BoatArg {
+    let span = arg.span();
     match arg {
-        BoatExpr::Value(value) => BoatArg::Const(value),
-        BoatExpr::Var(name) => BoatArg::FromKVS(name),
-        BoatExpr::Function { name, mut args } => {
-            let function = functions.get(&name).expect("Function is defined");
+        BoatExpr::Value(value, _) => BoatArg::Const(value),
+        BoatExpr::Var(name, _) => BoatArg::FromKVS(name),
+        BoatExpr::Function { name, args, .. } => {
+            let function = functions.get(&name).unwrap_or_else(|| panic!("{span}: function `{name}` is not defined"));
+            let params: &[String] = match function {
+                Function::InProgram { arg_names, .. } => arg_names,
+                Function::Predefined { .. } => &[],
+            };
             let mut translated_args = Vec::<BoatArg>::new();
-            args.reverse();
-            for arg in args {
-                translated_args.push(translate_expr(arg, instruction_index, instructions, functions, labeled_lines))
+            for (i, arg) in args.into_iter().enumerate().rev() {
+                match translate_expr(arg, code, functions, target) {
+                    // Parameters are bound one at a time, so a variable named like a parameter bound before it
+                    // is read before the call starts binding, like `a` in `f(b, a)` of `function f(a, b)`
+                    BoatArg::FromKVS(key) if params[..i.min(params.len())].contains(&key) => {
+                        code.push(BoatIns { cmd: BoatCmd::Push, args: vec![BoatArg::FromKVS(key)] }, span);
+                        translated_args.push(BoatArg::FromStack);
+                    }
+                    arg => translated_args.push(arg),
+                }
             }
             translated_args.reverse();
             match function {
-                Function::Predefined { translator } => {
-                    let translated_instrutions = translator(translated_args);
-                    *instruction_index += translated_instrutions.len() as u32;
-                    instructions.extend(translated_instrutions);
+                Function::Predefined { translator, .. } => {
+                    for ins in translator(translated_args) {
+                        code.push(ins, span);
+                    }
                 }
-                Function::InProgram { begin_pos, arg_names } => {
+                Function::InProgram { begin, arg_names, .. } => {
                     for (arg, name) in translated_args.into_iter().zip(arg_names) {
-                        *instruction_index += 1;
-                        instructions.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const(name.to_string()), arg] });
-                    }
-                    *instruction_index += 2;
-                    instructions.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const("return".to_owned()), BoatArg::Const(instruction_index.to_string())] });
-                    instructions.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Const(begin_pos.to_string())] });
-                    labeled_lines.insert(*instruction_index);
-                    instructions.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const("return".to_owned())] });
-                    *instruction_index += 1;
-                    for name in arg_names {
-                        *instruction_index += 1;
-                        instructions.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const(name.to_string())] });
+                        code.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const(name.to_string()), arg] }, span);
                     }
+                    let return_label = code.new_label();
+                    code.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const("return".to_owned()), BoatArg::Label(return_label)] }, span);
+                    code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(*begin)] }, span);
+                    code.place(return_label);
+                    // Arguments are dropped by the function epilogue
+                    code.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const("return".to_owned())] }, span);
                 }
             }
             BoatArg::FromStack
         },
-        BoatExpr::BinOp { lhs, op, rhs } => {
+        logical @ BoatExpr::BinOp { op: BoatOp::And | BoatOp::Or | BoatOp::Not, .. } => {
+            let false_label = code.new_label();
+            let end_label = code.new_label();
+            translate_condition(logical, false_label, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::Push, args: vec![BoatArg::Const("1".to_owned())] }, span);
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(end_label)] }, span);
+            code.place(false_label);
+            code.push(BoatIns { cmd: BoatCmd::Push, args: vec![BoatArg::Const("0".to_owned())] }, span);
+            code.place(end_label);
+            BoatArg::FromStack
+        },
+        BoatExpr::BinOp { lhs, op: BoatOp::Pow, rhs, .. } if !target.supports(BoatCmd::Pow) => {
+            let exponent = unrolled_exponent(&rhs).unwrap_or_else(|| panic!("{span}: exponent is not a small constant"));
+            translate_power(*lhs, exponent, code, functions, target)
+        },
+        BoatExpr::BinOp { lhs, op: op @ (BoatOp::Ne | BoatOp::Le | BoatOp::Ge), rhs, span } => {
+            let op = match op {
+                BoatOp::Ne => BoatOp::Eq,
+                BoatOp::Le => BoatOp::Gt,
+                _ => BoatOp::Lt,
+            };
+            let arg = translate_expr(BoatExpr::BinOp { lhs, op, rhs, span }, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::Eq, args: vec![arg, BoatArg::Const("0".to_owned())] }, span);
+            BoatArg::FromStack
+        },
+        BoatExpr::BinOp { lhs, op, rhs, .. } => {
             let mut bin_op_ins = BoatIns { cmd: op.into(), args: vec![] };
-            let rhs_arg = translate_expr(*rhs, instruction_index, instructions, functions, labeled_lines);
-            let lhs_arg = translate_expr(*lhs, instruction_index, instructions, functions, labeled_lines);
+            let rhs_arg = translate_expr(*rhs, code, functions, target);
+            let lhs_arg = translate_expr(*lhs, code, functions, target);
             if rhs_arg == BoatArg::FromStack && lhs_arg == BoatArg::FromStack {
                 bin_op_ins.args.push(rhs_arg);
                 bin_op_ins.args.push(lhs_arg);
@@ -66,9 +178,8 @@ pub fn translate_expr(arg: BoatExpr, instruction_index: &mut u32, instructions:
                 bin_op_ins.args.push(lhs_arg);
                 bin_op_ins.args.push(rhs_arg);
             }
-            instructions.push(bin_op_ins);
-            *instruction_index += 1;
+            code.push(bin_op_ins, span);
             BoatArg::FromStack
         },
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use pest::{error::{Error, ErrorVariant}, iterators::Pair, Parser, Span};
+use crate::boat_instructions::{BoatArg, BoatCmd, BoatIns};
+
+#[derive(pest_derive::Parser)]
+#[grammar = "instructions.pest"]
+pub struct InstructionParser;
+
+fn custom_error(message: String, span: Span) -> Box<Error<Rule>> {
+    Box::new(Error::new_from_span(ErrorVariant::CustomError { message }, span))
+}
+
+fn parse_instruction(pair: Pair<Rule>, line: u32, labeled_lines: &mut HashSet<u32>, check_args: bool) -> Result<BoatIns, Box<Error<Rule>>> {
+    let span = pair.as_span();
+    let mut inner = pair.into_inner().peekable();
+    if let Some(label) = inner.next_if(|pair| pair.as_rule() == Rule::label) {
+        let label_num = label.as_str().parse::<u32>().map_err(|e| custom_error(format!("invalid label: {e}"), label.as_span()))?;
+        if label_num != line {
+            return Err(custom_error(format!("label {label_num} is at instruction {line}"), label.as_span()));
+        }
+        labeled_lines.insert(line);
+    }
+    let cmd_pair = inner.next().unwrap();
+    let cmd = cmd_pair.as_str().parse::<BoatCmd>().map_err(|e| custom_error(e, cmd_pair.as_span()))?;
+    // Arguments are separated by a single space, the last one takes the rest of the instruction
+    let args = match inner.next().unwrap().as_str() {
+        "" => Vec::new(),
+        args => {
+            let args = args.strip_prefix(char::is_whitespace).unwrap_or(args);
+            args.splitn(cmd.arity().max(1), ' ').map(BoatArg::from).collect()
+        }
+    };
+    let ins = BoatIns { cmd, args };
+    if check_args {
+        ins.check_args().map_err(|e| custom_error(format!("`{cmd}` {e}"), span))?;
+    }
+    Ok(ins)
+}
+
+/// Parses instructions printed by `translated_to_string` or `translated_to_string2`.
+///
+/// `N|` labels are optional, but when present must match the position of the instruction.
+/// Labeled positions are collected into `labeled_lines`.
+/// Arguments are separated by single spaces and the last argument of the command takes the rest of the instruction,
+/// so constants may contain spaces or be empty.
+pub fn parse_instructions(s: &str, labeled_lines: &mut HashSet<u32>) -> Result<Vec<BoatIns>, Box<Error<Rule>>> {
+    parse(s, labeled_lines, true)
+}
+
+/// Like `parse_instructions`, but without checking arguments of commands, leaving that to the verifier
+pub fn parse_instructions_unchecked(s: &str, labeled_lines: &mut HashSet<u32>) -> Result<Vec<BoatIns>, Box<Error<Rule>>> {
+    parse(s, labeled_lines, false)
+}
+
+fn parse(s: &str, labeled_lines: &mut HashSet<u32>, check_args: bool) -> Result<Vec<BoatIns>, Box<Error<Rule>>> {
+    let mut parsed = InstructionParser::parse(Rule::instructions, s)?;
+    parsed
+        .next()
+        .unwrap()
+        .into_inner()
+        .filter(|pair| pair.as_rule() == Rule::instruction)
+        .enumerate()
+        .map(|(i, pair)| parse_instruction(pair, i as u32 + 1, labeled_lines, check_args))
+        .collect()
+}
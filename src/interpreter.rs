@@ -1,172 +1,326 @@
-use std::{collections::HashMap, io::{Write, BufRead, Read}, path::Display, thread::sleep, time::Duration};
+use std::{collections::HashMap, fmt::Display, io::Write, time::Duration};
 
-use crate::boat_instructions::{BoatCmd, BoatIns, BoatArg};
+use crate::boat_instructions::{ArgError, BoatCmd, BoatIns, BoatArg};
+use crate::boat_program::Span;
+use crate::clock::Clock;
+use crate::pin_bus::PinBus;
+use crate::screen::{DisplayObserver, Screen};
+use crate::source_map::SourceMap;
 
-type Kvs = HashMap<String, Vec<String>>;
+pub type Kvs = HashMap<String, Vec<String>>;
 
-fn get_arg(arg: &BoatArg, stack: &mut Vec<String>, kvs: &Kvs) -> String {
-    match arg {
-        BoatArg::Const(c) => { c.to_string() },
-        BoatArg::FromStack => { stack.pop().expect("stack has values") }
-        BoatArg::FromKVS(k) => { kvs.get(k).unwrap_or_else(|| panic!("kvs has key {k}")).last().expect("kvs has value").to_string() }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    BadArgs(ArgError),      // Arguments do not match the command
+    EmptyStack,             // `$` argument with nothing on the stack
+    MissingKey(String),     // `$key` argument with no value in key-value storage
+    NotANumber(String),     // Numeric operand can not be parsed
+    BadJumpTarget(String),  // Jump to a position that is not an instruction index
+    BadKvEntry(String),     // Stored key-value entry is not in `key:value` form
+    UnlinkedLabel(u32),     // Label argument left in the program
+    Io(String),             // Reading input or writing output failed
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub index: usize,
+    pub ins: BoatIns,
+    pub span: Option<Span>,
+    pub stack: Vec<String>,
+    pub kvs: Kvs,
+}
+
+impl Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use RuntimeErrorKind::*;
+        match self {
+            BadArgs(e) => write!(f, "{e}"),
+            EmptyStack => write!(f, "stack is empty"),
+            MissingKey(k) => write!(f, "key `{k}` has no value"),
+            NotANumber(s) => write!(f, "`{s}` is not a number"),
+            BadJumpTarget(s) => write!(f, "`{s}` is not a valid jump target"),
+            BadKvEntry(s) => write!(f, "`{s}` is not a valid key-value entry"),
+            UnlinkedLabel(l) => write!(f, "label @{l} is not linked"),
+            Io(e) => write!(f, "io error: {e}"),
+        }
     }
 }
 
-pub fn interpret(program: &Vec<BoatIns>, mut output: impl Write, mut input: impl BufRead, debug: bool) {
-    let mut stack = Vec::<String>::new();
-    let mut kvs = Kvs::new();
-    let mut i = 0;
-    let l = program.len();
-    while i < l {
-        let ins = &program[i];
-        if debug {
-            writeln!(output, "{}| {ins} -- {:?} -- {:?}", i + 1, stack, kvs);
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "runtime error at {}| {}", self.index + 1, self.ins)?;
+        if let Some(span) = self.span {
+            write!(f, " (line {span})")?;
         }
-        let BoatIns {args, cmd} = ins;
-        match cmd {
-            BoatCmd::Push => {
-                let arg = get_arg(args.first().expect("Push has 1 arg"), &mut stack, &kvs);
-                stack.push(arg);
-            },
-            BoatCmd::Goto => {
-                i = get_arg(args.first().expect("Goto has 1 arg"), &mut stack, &kvs).parse::<usize>().expect("Goto arg is integer") - 1usize;
-                continue;
-            },
-            BoatCmd::Input | BoatCmd::InputAsync => {
-                let mut s = String::new();
-                input.read_line(&mut s).expect("success read");
-                stack.push(s.trim().to_string());
-            },
-            BoatCmd::Output => {
-                let out_num = get_arg(args.get(0).expect("Output has 1 arg"), &mut stack, &kvs);
-                let out = get_arg(args.get(1).expect("Output has 2 args"), &mut stack, &kvs);
-                writeln!(output, "{out_num} <- {out}");
-            },
-            BoatCmd::Add => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.trim().parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.trim().parse::<f32>().expect("argument 2 is f32");
-                stack.push((parsed1 + parsed2).to_string());
-            },
-            BoatCmd::Sub => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.trim().parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.trim().parse::<f32>().expect("argument 2 is f32");
-                stack.push((parsed1 - parsed2).to_string());
-            },
-            BoatCmd::Mul => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.trim().parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.trim().parse::<f32>().expect("argument 2 is f32");
-                stack.push((parsed1 * parsed2).to_string());
-            },
-            BoatCmd::Div => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.trim().parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.trim().parse::<f32>().expect("argument 2 is f32");
-                stack.push((parsed1 / parsed2).to_string());
-            },
-            BoatCmd::Conc => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                stack.push(format!("{arg1}{arg2}").to_string());
-            },
-            BoatCmd::KVSet => {
-                let arg1 = get_arg(args.get(0).expect("kvset has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("kvset has 2 args"), &mut stack, &kvs);
-                kvs.entry(arg1).and_modify(|e| e.push(arg2.clone())).or_insert(vec![arg2]);
-            },
-            BoatCmd::KVDel => {
-                let arg1 = get_arg(args.get(0).expect("kvdel has 1 arg"), &mut stack, &kvs);
-                kvs.entry(arg1).and_modify(|e| { e.pop(); });
-            },
-            BoatCmd::Cmp => {
-                let arg1 = get_arg(args.get(0).expect("cmp has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("cmp has 1 arg"), &mut stack, &kvs);
-                if arg1.parse::<f32>().expect("arg1 is numeric") == 0. {
-                    i = arg2.parse::<usize>().expect("arg2 is u32") - 1;
-                    continue;
-                }
-            },
-            BoatCmd::Eq => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                stack.push(((arg1 == arg2) as usize as f32).to_string());
-            },
-            BoatCmd::Gt => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.parse::<f32>().expect("argument 2 is f32");
-                stack.push(((parsed1 > parsed2) as usize as f32).to_string());
-            },
-            BoatCmd::Lt => {
-                let arg1 = get_arg(args.get(0).expect("operation has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("operation has 2 args"), &mut stack, &kvs);
-                let parsed1 = arg1.parse::<f32>().expect("argument 1 is f32");
-                let parsed2 = arg2.parse::<f32>().expect("argument 2 is f32");
-                stack.push(((parsed1 < parsed2) as usize as f32).to_string());
-            },
-            BoatCmd::KVReSet => {
-                let arg1 = get_arg(args.get(0).expect("kvset has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("kvset has 2 args"), &mut stack, &kvs);
-                kvs.entry(arg1).and_modify(|e| { e.pop(); e.push(arg2.clone()) }).or_insert(vec![arg2]);
-            },
-            BoatCmd::Sleep => {
-                let arg1 = get_arg(args.get(0).expect("kvset has 1 arg"), &mut stack, &kvs);
-                sleep(Duration::from_secs_f64(arg1.parse().expect("arg1 is f64")));
-            }
-            BoatCmd::Display => {
-                unimplemented!();
+        writeln!(f, ": {}", self.kind)?;
+        writeln!(f, "  stack: {:?}", self.stack)?;
+        let mut keys = self.kvs.keys().collect::<Vec<_>>();
+        keys.sort();
+        write!(f, "  kvs: {{")?;
+        for (i, key) in keys.into_iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{sep}{key}: {:?}", self.kvs[key])?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+fn get_arg(args: &[BoatArg], n: usize, stack: &mut Vec<String>, kvs: &Kvs) -> Result<String, RuntimeErrorKind> {
+    // Argument count is checked before the instruction is executed
+    match &args[n] {
+        BoatArg::Const(c) => Ok(c.to_string()),
+        BoatArg::FromStack => stack.pop().ok_or(RuntimeErrorKind::EmptyStack),
+        BoatArg::FromKVS(k) => kvs.get(k).and_then(|values| values.last()).cloned().ok_or_else(|| RuntimeErrorKind::MissingKey(k.to_string())),
+        BoatArg::Label(l) => Err(RuntimeErrorKind::UnlinkedLabel(*l)),
+    }
+}
+
+fn parse_num(s: &str) -> Result<f32, RuntimeErrorKind> {
+    s.parse::<f32>().map_err(|_| RuntimeErrorKind::NotANumber(s.to_string()))
+}
+
+fn parse_duration(s: String) -> Result<Duration, RuntimeErrorKind> {
+    let secs = s.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+    secs.ok_or(RuntimeErrorKind::NotANumber(s))
+}
+
+fn parse_target(s: &str, l: usize) -> Result<usize, RuntimeErrorKind> {
+    match s.parse::<usize>() {
+        Ok(target) if (1..=l + 1).contains(&target) => Ok(target - 1),
+        _ => Err(RuntimeErrorKind::BadJumpTarget(s.to_string())),
+    }
+}
+
+fn numeric_op(args: &[BoatArg], stack: &mut Vec<String>, kvs: &Kvs, op: impl Fn(f32, f32) -> f32) -> Result<(), RuntimeErrorKind> {
+    let arg1 = get_arg(args, 0, stack, kvs)?;
+    let arg2 = get_arg(args, 1, stack, kvs)?;
+    let parsed1 = parse_num(arg1.trim())?;
+    let parsed2 = parse_num(arg2.trim())?;
+    stack.push(op(parsed1, parsed2).to_string());
+    Ok(())
+}
+
+/// Remainder of division rounded down, with the sign of `b`
+pub fn floored_mod(a: f32, b: f32) -> f32 {
+    let r = a % b;
+    if r != 0. && (r < 0.) != (b < 0.) { r + b } else { r }
+}
+
+fn compare_op(args: &[BoatArg], stack: &mut Vec<String>, kvs: &Kvs, op: impl Fn(f32, f32) -> bool) -> Result<(), RuntimeErrorKind> {
+    let arg1 = get_arg(args, 0, stack, kvs)?;
+    let arg2 = get_arg(args, 1, stack, kvs)?;
+    let parsed1 = parse_num(&arg1)?;
+    let parsed2 = parse_num(&arg2)?;
+    stack.push((op(parsed1, parsed2) as usize as f32).to_string());
+    Ok(())
+}
+
+#[derive(Default)]
+struct State {
+    stack: Vec<String>,
+    kvs: Kvs,
+    screen: Screen,
+}
+
+// Executes one instruction and returns index of the next one
+fn execute(
+    i: usize,
+    ins: &BoatIns,
+    l: usize,
+    state: &mut State,
+    bus: &mut impl PinBus,
+    display: &mut impl DisplayObserver,
+    clock: &mut dyn Clock,
+) -> Result<usize, RuntimeErrorKind> {
+    ins.check_args().map_err(RuntimeErrorKind::BadArgs)?;
+    let State { stack, kvs, screen } = state;
+    let BoatIns {args, cmd} = ins;
+    match cmd {
+        BoatCmd::Push => {
+            let arg = get_arg(args, 0, stack, kvs)?;
+            stack.push(arg);
+        },
+        BoatCmd::Goto => {
+            let target = get_arg(args, 0, stack, kvs)?;
+            return parse_target(&target, l);
+        },
+        BoatCmd::Input => {
+            let pin = get_arg(args, 0, stack, kvs)?;
+            let value = bus.input(&pin, None, clock).map_err(|e| RuntimeErrorKind::Io(e.to_string()))?;
+            stack.push(value.unwrap_or_default());
+        },
+        BoatCmd::InputAsync => {
+            let pin = get_arg(args, 0, stack, kvs)?;
+            let timeout = get_arg(args, 1, stack, kvs)?;
+            let timeout = parse_duration(timeout)?;
+            let value = bus.input(&pin, Some(timeout), clock).map_err(|e| RuntimeErrorKind::Io(e.to_string()))?;
+            // Empty string is pushed when nothing came before the timeout
+            stack.push(value.unwrap_or_default());
+        },
+        BoatCmd::Output => {
+            let out_num = get_arg(args, 0, stack, kvs)?;
+            let out = get_arg(args, 1, stack, kvs)?;
+            bus.output(&out_num, &out, clock).map_err(|e| RuntimeErrorKind::Io(e.to_string()))?;
+        },
+        BoatCmd::Add => numeric_op(args, stack, kvs, |a, b| a + b)?,
+        BoatCmd::Sub => numeric_op(args, stack, kvs, |a, b| a - b)?,
+        BoatCmd::Mul => numeric_op(args, stack, kvs, |a, b| a * b)?,
+        BoatCmd::Div => numeric_op(args, stack, kvs, |a, b| a / b)?,
+        BoatCmd::Mod => numeric_op(args, stack, kvs, floored_mod)?,
+        BoatCmd::IntDiv => numeric_op(args, stack, kvs, |a, b| (a / b).floor())?,
+        BoatCmd::Pow => numeric_op(args, stack, kvs, f32::powf)?,
+        BoatCmd::Conc => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            stack.push(format!("{arg1}{arg2}"));
+        },
+        BoatCmd::KVSet => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            kvs.entry(arg1).and_modify(|e| e.push(arg2.clone())).or_insert(vec![arg2]);
+        },
+        BoatCmd::KVDel => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            kvs.entry(arg1).and_modify(|e| { e.pop(); });
+        },
+        BoatCmd::Cmp => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            if parse_num(&arg1)? == 0. {
+                return parse_target(&arg2, l);
             }
-            BoatCmd::DisplayClear => {
-                unimplemented!();
+        },
+        BoatCmd::Eq => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            stack.push(((arg1 == arg2) as usize as f32).to_string());
+        },
+        BoatCmd::Gt => compare_op(args, stack, kvs, |a, b| a > b)?,
+        BoatCmd::Lt => compare_op(args, stack, kvs, |a, b| a < b)?,
+        BoatCmd::KVReSet => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            kvs.entry(arg1).and_modify(|e| { e.pop(); e.push(arg2.clone()) }).or_insert(vec![arg2]);
+        },
+        BoatCmd::Sleep => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            clock.sleep(parse_duration(arg1)?);
+        }
+        BoatCmd::Display => {
+            let x = get_arg(args, 0, stack, kvs)?;
+            let y = get_arg(args, 1, stack, kvs)?;
+            let (x, y) = (parse_num(&x)?, parse_num(&y)?);
+            if x >= 0. && y >= 0. {
+                screen.paint(x as usize, y as usize);
             }
-            BoatCmd::Store => {
-                let arg1 = get_arg(args.get(0).expect("kvset has 1 arg"), &mut stack, &kvs);
-                let arg2 = get_arg(args.get(1).expect("kvset has 2 args"), &mut stack, &kvs);
-                match arg1.as_str() {
-                    "s" => {
-                        stack = arg2.split(";").map_while(|s| if !s.is_empty() {Some(s.to_string())} else {None}).collect();
-                    }
-                    "kv" => {
-                        kvs.clear();
-                        for s in arg2.split(",").take_while(|s| !s.is_empty()) {
-                            let mut splitted = s.split(':');
-                            let key = splitted.next().expect("kv has key").to_string();
-                            let value = splitted.next().expect("kv has value").to_string();
-                            match kvs.entry(key) {
-                                std::collections::hash_map::Entry::Occupied(mut e) => {
-                                    e.get_mut().push(value);
-                                }
-                                std::collections::hash_map::Entry::Vacant(e) => {
-                                    e.insert(vec![value]);
-                                }
-                            }
-                        }
-                    }
-                    _ => {
+            display.update(screen);
+        }
+        BoatCmd::DisplayClear => {
+            screen.clear();
+            display.update(screen);
+        }
+        BoatCmd::Store => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            let arg2 = get_arg(args, 1, stack, kvs)?;
+            match arg1.as_str() {
+                "s" => {
+                    *stack = arg2.split(';').map_while(|s| if !s.is_empty() {Some(s.to_string())} else {None}).collect();
+                }
+                "kv" => {
+                    kvs.clear();
+                    for s in arg2.split(',').take_while(|s| !s.is_empty()) {
+                        let mut splitted = s.split(':');
+                        let key = splitted.next().unwrap_or_default().to_string();
+                        let value = splitted.next().ok_or_else(|| RuntimeErrorKind::BadKvEntry(s.to_string()))?.to_string();
+                        kvs.entry(key).or_default().push(value);
                     }
                 }
+                _ => {
+                }
             }
-            BoatCmd::Clear => {
-                let arg1 = get_arg(args.get(0).expect("kvset has 1 arg"), &mut stack, &kvs);
-                match arg1.as_str() {
-                    "s" => {
-                        stack.clear();
-                    }
-                    "kv" => {
-                        kvs.clear();
-                    }
-                    _ => {
-                    }
+        }
+        BoatCmd::Clear => {
+            let arg1 = get_arg(args, 0, stack, kvs)?;
+            match arg1.as_str() {
+                "s" => {
+                    stack.clear();
+                }
+                "kv" => {
+                    kvs.clear();
+                }
+                _ => {
                 }
             }
-        };
-        i += 1;
+        }
+    };
+    Ok(i + 1)
+}
+
+/// Runs the program, writing every executed instruction with the stack and kvs to `trace` if it is given.
+/// Source lines from `source_map` are added to the trace and runtime errors. Returns kvs left at the end
+pub fn interpret(
+    program: &[BoatIns],
+    bus: &mut impl PinBus,
+    display: &mut impl DisplayObserver,
+    clock: &mut dyn Clock,
+    source_map: Option<&SourceMap>,
+    mut trace: Option<&mut dyn Write>,
+) -> Result<Kvs, Box<RuntimeError>> {
+    let mut state = State::default();
+    let mut i = 0;
+    let l = program.len();
+    while i < l {
+        let ins = &program[i];
+        let span = source_map.and_then(|source_map| source_map.get(i as u32 + 1));
+        if let Some(trace) = trace.as_mut() {
+            match span {
+                Some(span) => { let _ = writeln!(trace, "{}| {ins} -- {:?} -- {:?} -- line {span}", i + 1, state.stack, state.kvs); }
+                None => { let _ = writeln!(trace, "{}| {ins} -- {:?} -- {:?}", i + 1, state.stack, state.kvs); }
+            }
+        }
+        // Errors show the state before the instruction, kvs are only changed after all arguments are read
+        let stack = (ins.pops() > 0).then(|| state.stack.clone());
+        i = execute(i, ins, l, &mut state, bus, display, clock).map_err(|kind| Box::new(RuntimeError {
+            kind,
+            index: i,
+            ins: ins.clone(),
+            span,
+            stack: stack.unwrap_or_else(|| state.stack.clone()),
+            kvs: state.kvs.clone(),
+        }))?;
+    }
+    Ok(state.kvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::instruction_parser::parse_instructions;
+    use crate::pin_bus::{RecordingBus, ScriptedBus};
+
+    #[test]
+    fn error_shows_stack_before_the_instruction() {
+        let program = parse_instructions("p x;p 1;+ $ $;", &mut HashSet::new()).unwrap();
+        let e = interpret(&program, &mut ScriptedBus::new(), &mut (), &mut SimulatedClock::new(), None, None).unwrap_err();
+        assert_eq!(e.kind, RuntimeErrorKind::NotANumber("x".into()));
+        assert_eq!(e.index, 2);
+        assert_eq!(e.stack, ["x", "1"]);
+    }
+
+    #[test]
+    fn mod_and_int_div_round_down() {
+        assert_eq!(floored_mod(-7., 3.), 2.);
+        assert_eq!(floored_mod(7., -3.), -2.);
+        assert_eq!(floored_mod(-6., 3.), 0.);
+        let program = parse_instructions("% -7 3;o 1 $;% 7 -3;o 1 $;~/ -7 2;o 1 $;~/ 7 2;o 1 $;", &mut HashSet::new()).unwrap();
+        let mut bus = RecordingBus::new(ScriptedBus::new());
+        interpret(&program, &mut bus, &mut (), &mut SimulatedClock::new(), None, None).unwrap();
+        assert_eq!(bus.outputs("1").into_iter().map(|(_, value)| value).collect::<Vec<&str>>(), ["2", "-2", "-4", "3"]);
     }
 }
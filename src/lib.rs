@@ -6,4 +6,14 @@ pub mod boat_instructions;
 pub mod boat_program;
 pub mod program_translator;
 pub mod interpreter;
+pub mod screen;
+pub mod pin_bus;
+pub mod clock;
 pub mod program_optimizer;
+pub mod program_checker;
+pub mod source_map;
+pub mod linker;
+pub mod peephole_optimizer;
+pub mod verifier;
+pub mod instruction_parser;
+pub mod compiler;
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::boat_instructions::{BoatArg, BoatCmd, BoatIns, Label};
+use crate::boat_program::Span;
+use crate::source_map::SourceMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Ins(BoatIns, Option<Span>),
+    Label(Label), // Position of the next instruction
+}
+
+/// Instructions with symbolic labels instead of instruction indexes
+#[derive(Debug, Clone, Default)]
+pub struct Code {
+    pub items: Vec<Item>,
+    next_label: Label,
+}
+
+impl Code {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    pub fn push(&mut self, ins: BoatIns, span: Span) {
+        self.items.push(Item::Ins(ins, Some(span)));
+    }
+
+    pub fn place(&mut self, label: Label) {
+        self.items.push(Item::Label(label));
+    }
+}
+
+/// Lays out the code, replacing labels with 1-based instruction indexes.
+/// Referenced indexes are collected into `labeled_lines` and instruction spans into `source_map`
+pub fn link(code: Code, labeled_lines: &mut HashSet<u32>, source_map: &mut SourceMap) -> Vec<BoatIns> {
+    let mut positions = HashMap::<Label, u32>::new();
+    let mut index = 1;
+    for item in code.items.iter() {
+        match item {
+            Item::Ins(..) => index += 1,
+            Item::Label(label) => {
+                positions.insert(*label, index);
+            }
+        }
+    }
+    let mut instructions = Vec::<BoatIns>::new();
+    for item in code.items {
+        let Item::Ins(mut ins, span) = item else {
+            continue;
+        };
+        for arg in ins.args.iter_mut() {
+            if let BoatArg::Label(label) = arg {
+                let position = *positions.get(label).unwrap_or_else(|| panic!("label {label} is placed"));
+                labeled_lines.insert(position);
+                *arg = BoatArg::Const(position.to_string());
+            }
+        }
+        instructions.push(ins);
+        if let Some(span) = span {
+            source_map.insert(instructions.len() as u32, span);
+        }
+    }
+    instructions
+}
+
+// Keys of `ka`/`kr` holding return addresses, read by jumps like `g $return`
+fn address_keys(instructions: &[BoatIns]) -> HashSet<String> {
+    instructions.iter().filter_map(|ins| match ins.target() {
+        Some(BoatArg::FromKVS(key)) => Some(key.clone()),
+        _ => None,
+    }).collect()
+}
+
+/// Turns jump targets and stored return addresses of linked instructions back into labels
+pub fn unlink(instructions: Vec<BoatIns>, source_map: &SourceMap) -> Code {
+    let len = instructions.len() as u32;
+    let keys = address_keys(&instructions);
+    let mut code = Code::new();
+    let mut labels = HashMap::<u32, Label>::new();
+    let mut items = Vec::<Item>::new();
+    for (i, mut ins) in instructions.into_iter().enumerate() {
+        let target = match (&ins.cmd, ins.args.as_mut_slice()) {
+            (BoatCmd::KVSet | BoatCmd::KVReSet, [BoatArg::Const(key), target]) if keys.contains(key) => Some(target),
+            _ => ins.target_mut(),
+        };
+        if let Some(target) = target {
+            let position = match target {
+                BoatArg::Const(t) => t.parse::<u32>().ok().filter(|t| (1..=len + 1).contains(t)),
+                _ => None,
+            };
+            if let Some(position) = position {
+                *target = BoatArg::Label(*labels.entry(position).or_insert_with(|| code.new_label()));
+            }
+        }
+        items.push(Item::Ins(ins, source_map.get(i as u32 + 1)));
+    }
+    for (i, item) in items.into_iter().enumerate() {
+        if let Some(label) = labels.get(&(i as u32 + 1)) {
+            code.place(*label);
+        }
+        code.items.push(item);
+    }
+    if let Some(label) = labels.get(&(len + 1)) {
+        code.place(*label);
+    }
+    code
+}
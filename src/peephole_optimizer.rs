@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::boat_instructions::{BoatArg, BoatCmd, BoatIns, Label};
+use crate::linker::{Code, Item};
+
+fn is_jump_to(ins: &BoatIns, label: Label) -> bool {
+    match (&ins.cmd, ins.args.as_slice()) {
+        (BoatCmd::Goto, [target]) => *target == BoatArg::Label(label),
+        // `c $ L` still has to pop the value
+        (BoatCmd::Cmp, [value, target]) => *value != BoatArg::FromStack && *target == BoatArg::Label(label),
+        _ => false,
+    }
+}
+
+fn remove_unused_labels(items: &mut Vec<Item>) -> bool {
+    let used = items.iter().flat_map(|item| match item {
+        Item::Ins(ins, _) => ins.args.iter().filter_map(|arg| match arg {
+            BoatArg::Label(label) => Some(*label),
+            _ => None,
+        }).collect(),
+        Item::Label(_) => vec![],
+    }).collect::<HashSet<Label>>();
+    let len = items.len();
+    items.retain(|item| !matches!(item, Item::Label(label) if !used.contains(label)));
+    items.len() != len
+}
+
+// Jumps to a `g` go straight to its target
+fn thread_jumps(items: &mut [Item]) -> bool {
+    let mut forwards = HashMap::<Label, BoatArg>::new();
+    for (i, item) in items.iter().enumerate() {
+        let Item::Label(label) = item else {
+            continue;
+        };
+        let next = items[i + 1..].iter().find_map(|item| match item {
+            Item::Ins(ins, _) => Some(ins),
+            Item::Label(_) => None,
+        });
+        if let Some(BoatIns { cmd: BoatCmd::Goto, args }) = next {
+            if let [target] = args.as_slice() {
+                if *target != BoatArg::FromStack {
+                    forwards.insert(*label, target.clone());
+                }
+            }
+        }
+    }
+    let resolve = |label: Label| {
+        let mut target = BoatArg::Label(label);
+        let mut visited = HashSet::<Label>::new();
+        while let BoatArg::Label(label) = target {
+            match forwards.get(&label) {
+                // Jump cycles are left as they are
+                Some(next) if visited.insert(label) => target = next.clone(),
+                _ => break,
+            }
+        }
+        target
+    };
+    let mut changed = false;
+    for item in items.iter_mut() {
+        let Item::Ins(ins, _) = item else {
+            continue;
+        };
+        if let Some(target) = ins.target_mut() {
+            if let BoatArg::Label(label) = target {
+                let resolved = resolve(*label);
+                if resolved != *target {
+                    *target = resolved;
+                    changed = true;
+                }
+            }
+        }
+    }
+    changed
+}
+
+// `c` with a constant value either always jumps or never does
+fn fold_const_conditions(items: &mut Vec<Item>) -> bool {
+    let mut changed = false;
+    items.retain_mut(|item| {
+        let Item::Ins(ins, _) = item else {
+            return true;
+        };
+        let (BoatCmd::Cmp, [BoatArg::Const(value), target]) = (&ins.cmd, ins.args.as_slice()) else {
+            return true;
+        };
+        let Ok(value) = value.parse::<f32>() else {
+            return true;
+        };
+        changed = true;
+        if value == 0. {
+            *ins = BoatIns { cmd: BoatCmd::Goto, args: vec![target.clone()] };
+            true
+        } else {
+            false
+        }
+    });
+    changed
+}
+
+fn remove_jumps_to_next(items: &mut Vec<Item>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < items.len() {
+        if let Item::Ins(ins, _) = &items[i] {
+            let mut next_labels = items[i + 1..].iter().map_while(|item| match item {
+                Item::Label(label) => Some(*label),
+                Item::Ins(..) => None,
+            });
+            if next_labels.any(|label| is_jump_to(ins, label)) {
+                items.remove(i);
+                changed = true;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// `p X` followed by an instruction taking `$` passes X directly
+fn fold_pushes(items: &mut Vec<Item>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < items.len() {
+        if let [Item::Ins(BoatIns { cmd: BoatCmd::Push, args }, _), Item::Ins(next, _)] = &mut items[i..i + 2] {
+            if let [value] = args.as_slice() {
+                if *value != BoatArg::FromStack {
+                    // Arguments are read in order, so the first `$` gets the pushed value
+                    if let Some(arg) = next.args.iter_mut().find(|arg| **arg == BoatArg::FromStack) {
+                        *arg = value.clone();
+                        items.remove(i);
+                        changed = true;
+                        continue;
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// `ka t X` immediately followed by `kd t`, a popped value is still discarded
+fn remove_set_delete_pairs(items: &mut Vec<Item>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < items.len() {
+        if let [Item::Ins(set, _), Item::Ins(delete, _)] = &items[i..i + 2] {
+            if let ((BoatCmd::KVSet, [key, value]), (BoatCmd::KVDel, [deleted])) = ((&set.cmd, set.args.as_slice()), (&delete.cmd, delete.args.as_slice())) {
+                if key == deleted && *key != BoatArg::FromStack {
+                    if *value == BoatArg::FromStack {
+                        items.splice(i..i + 2, [Item::Ins(BoatIns::discard(), None)]);
+                    } else {
+                        items.drain(i..i + 2);
+                    }
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// Instructions after `g` up to the next label are never executed
+fn remove_dead_code(items: &mut Vec<Item>) -> bool {
+    let mut changed = false;
+    let mut dead = false;
+    items.retain(|item| match item {
+        Item::Label(_) => {
+            dead = false;
+            true
+        }
+        Item::Ins(_, _) if dead => {
+            changed = true;
+            false
+        }
+        Item::Ins(ins, _) => {
+            dead = ins.cmd == BoatCmd::Goto;
+            true
+        }
+    });
+    changed
+}
+
+// Jumps to popped values or to keys not only holding labels may go to any instruction
+fn has_dynamic_jumps(items: &[Item]) -> bool {
+    let mut address_keys = HashMap::<&str, bool>::new(); // Key -> only labels are stored in it
+    for item in items {
+        let Item::Ins(ins, _) = item else {
+            continue;
+        };
+        if let (BoatCmd::KVSet | BoatCmd::KVReSet, [key, value]) = (&ins.cmd, ins.args.as_slice()) {
+            let BoatArg::Const(key) = key else {
+                return true;
+            };
+            let label = matches!(value, BoatArg::Label(_));
+            *address_keys.entry(key).or_insert(label) &= label;
+        }
+    }
+    items.iter().any(|item| match item {
+        Item::Ins(ins, _) => match ins.target() {
+            Some(BoatArg::FromStack) => true,
+            Some(BoatArg::FromKVS(key)) => !address_keys.get(key.as_str()).copied().unwrap_or(false),
+            _ => false,
+        },
+        Item::Label(_) => false,
+    })
+}
+
+/// Removes jumps to the next instruction, threads jumps through `g` chains, resolves `c` of constants,
+/// passes pushed values directly to the next instruction, drops `ka`/`kd` pairs and unreachable instructions.
+/// Code with jumps to computed targets is left as it is, as any instruction may be reached
+pub fn optimize(code: &mut Code) {
+    let items = &mut code.items;
+    if has_dynamic_jumps(items) {
+        return;
+    }
+    loop {
+        let mut changed = remove_unused_labels(items);
+        changed |= thread_jumps(items);
+        changed |= fold_const_conditions(items);
+        changed |= remove_jumps_to_next(items);
+        changed |= fold_pushes(items);
+        changed |= remove_set_delete_pairs(items);
+        changed |= remove_dead_code(items);
+        if !changed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::boat_instructions::translated_to_string;
+    use crate::instruction_parser::parse_instructions;
+    use crate::linker::{link, unlink};
+    use crate::source_map::SourceMap;
+
+    fn optimized(instructions: &str) -> String {
+        let mut code = unlink(parse_instructions(instructions, &mut HashSet::new()).unwrap(), &SourceMap::new());
+        optimize(&mut code);
+        translated_to_string(link(code, &mut HashSet::new(), &mut SourceMap::new()))
+    }
+
+    #[test]
+    fn computed_jump_targets_keep_code() {
+        for instructions in ["p 4;g $;o 1 dead;o 1 hi;", "i 1;g $;o 1 a;o 1 b;", "i 1;ka t $;g $t;o 1 a;o 1 b;"] {
+            assert_eq!(optimized(instructions), instructions);
+        }
+        assert_eq!(optimized("ka return 4;g 3;g $return;kd return;o 1 a;"), "ka return 3;g $return;kd return;o 1 a;");
+    }
+
+    #[test]
+    fn popped_value_of_set_delete_pair_is_discarded() {
+        assert_eq!(optimized("i 1;ka t $;kd t;o 1 a;"), "i 1;kr ~ $;o 1 a;");
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::{HashMap, VecDeque}, io::{self, BufRead, Write}, sync::mpsc::{self, Receiver, TryRecvError}, thread, time::Duration};
+
+use crate::clock::Clock;
+
+// How often a timed out read checks for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Devices connected to the pins, used by `i`, `ia` and `o`
+pub trait PinBus {
+    /// Writes value to the pin
+    fn output(&mut self, pin: &str, value: &str, clock: &mut dyn Clock) -> io::Result<()>;
+    /// Reads value from the pin. `timeout` is set for `ia`, and `None` is returned when it runs out on `clock`
+    fn input(&mut self, pin: &str, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>>;
+}
+
+/// Source of input lines for `StdioBus`
+pub trait LineSource {
+    /// Reads the next line without the line ending, giving up after `timeout` on `clock` if it is set.
+    /// Empty line is returned at the end of input
+    fn next_line(&mut self, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>>;
+}
+
+/// Lines of a reader. Reads block, so timeouts are never reached
+pub struct BlockingLines<R: BufRead>(pub R);
+
+impl<R: BufRead> LineSource for BlockingLines<R> {
+    fn next_line(&mut self, _timeout: Option<Duration>, _clock: &mut dyn Clock) -> io::Result<Option<String>> {
+        let mut s = String::new();
+        self.0.read_line(&mut s)?;
+        Ok(Some(s.trim().to_string()))
+    }
+}
+
+/// Lines of a reader read by a background thread, so waiting for them can time out.
+/// With a timeout lines are polled, sleeping on the clock in between.
+/// Simulated clocks do not advance while the reader waits, so there a read blocks and the timeout runs out only at the end of input
+pub struct ThreadedLines {
+    receiver: Receiver<io::Result<String>>,
+}
+
+impl ThreadedLines {
+    pub fn spawn<R: BufRead + Send + 'static>(reader: R) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let failed = line.is_err();
+                if sender.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        ThreadedLines { receiver }
+    }
+}
+
+impl LineSource for ThreadedLines {
+    fn next_line(&mut self, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>> {
+        let Some(timeout) = timeout else {
+            return match self.receiver.recv() {
+                Ok(line) => Ok(Some(line?.trim().to_string())),
+                Err(_) => Ok(Some(String::new())),
+            };
+        };
+        if clock.is_simulated() {
+            return match self.receiver.recv() {
+                Ok(line) => Ok(Some(line?.trim().to_string())),
+                Err(_) => {
+                    clock.sleep(timeout);
+                    Ok(None)
+                }
+            };
+        }
+        let deadline = clock.now() + timeout;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(line) => return Ok(Some(line?.trim().to_string())),
+                Err(TryRecvError::Disconnected) => return Ok(Some(String::new())),
+                Err(TryRecvError::Empty) => {}
+            }
+            let now = clock.now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            clock.sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// Reads every pin line by line from `input` and writes outputs as `pin <- value` lines
+pub struct StdioBus<L: LineSource, W: Write> {
+    pub input: L,
+    pub output: W,
+}
+
+impl<L: LineSource, W: Write> StdioBus<L, W> {
+    pub fn new(input: L, output: W) -> Self {
+        StdioBus { input, output }
+    }
+}
+
+impl<L: LineSource, W: Write> PinBus for StdioBus<L, W> {
+    fn output(&mut self, pin: &str, value: &str, _clock: &mut dyn Clock) -> io::Result<()> {
+        writeln!(self.output, "{pin} <- {value}")
+    }
+
+    fn input(&mut self, _pin: &str, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>> {
+        self.input.next_line(timeout, clock)
+    }
+}
+
+/// Answers inputs from prepared per-pin queues and drops outputs.
+/// `ia` on an empty queue waits for the whole timeout and times out, `i` fails
+#[derive(Debug, Default)]
+pub struct ScriptedBus {
+    inputs: HashMap<String, VecDeque<String>>,
+}
+
+impl ScriptedBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues values to be read from the pin in order
+    pub fn with_inputs(mut self, pin: &str, values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.inputs.entry(pin.to_owned()).or_default().extend(values.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl PinBus for ScriptedBus {
+    fn output(&mut self, _pin: &str, _value: &str, _clock: &mut dyn Clock) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn input(&mut self, pin: &str, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>> {
+        match (self.inputs.get_mut(pin).and_then(|values| values.pop_front()), timeout) {
+            (Some(value), _) => Ok(Some(value)),
+            (None, Some(timeout)) => {
+                clock.sleep(timeout);
+                Ok(None)
+            }
+            (None, None) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("no input left for pin {pin}"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinEvent {
+    Input { pin: String, value: String },
+    InputTimeout { pin: String },
+    Output { pin: String, value: String },
+}
+
+/// Passes everything to the inner bus and records it with the clock time
+#[derive(Debug, Default)]
+pub struct RecordingBus<B: PinBus> {
+    pub inner: B,
+    pub events: Vec<(Duration, PinEvent)>,
+}
+
+impl<B: PinBus> RecordingBus<B> {
+    pub fn new(inner: B) -> Self {
+        RecordingBus { inner, events: Vec::new() }
+    }
+
+    /// Values written to the pin in order, with the time of writing
+    pub fn outputs(&self, pin: &str) -> Vec<(Duration, &str)> {
+        self.events
+            .iter()
+            .filter_map(|(at, event)| match event {
+                PinEvent::Output { pin: p, value } if p == pin => Some((*at, value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl<B: PinBus> PinBus for RecordingBus<B> {
+    fn output(&mut self, pin: &str, value: &str, clock: &mut dyn Clock) -> io::Result<()> {
+        self.inner.output(pin, value, clock)?;
+        self.events.push((clock.now(), PinEvent::Output { pin: pin.to_owned(), value: value.to_owned() }));
+        Ok(())
+    }
+
+    fn input(&mut self, pin: &str, timeout: Option<Duration>, clock: &mut dyn Clock) -> io::Result<Option<String>> {
+        let value = self.inner.input(pin, timeout, clock)?;
+        self.events.push((clock.now(), match &value {
+            Some(value) => PinEvent::Input { pin: pin.to_owned(), value: value.clone() },
+            None => PinEvent::InputTimeout { pin: pin.to_owned() },
+        }));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    // Delivers its data only after a real delay
+    struct SlowReader(&'static [u8]);
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(50));
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn simulated_timeout_waits_for_slow_input() {
+        let mut lines = ThreadedLines::spawn(BufReader::new(SlowReader(b"7\n")));
+        let mut clock = SimulatedClock::new();
+        assert_eq!(lines.next_line(Some(Duration::from_millis(1)), &mut clock).unwrap(), Some("7".to_string()));
+        assert_eq!(clock.now(), Duration::ZERO);
+        assert_eq!(lines.next_line(Some(Duration::from_millis(500)), &mut clock).unwrap(), None);
+        assert_eq!(clock.now(), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,290 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, ops::RangeInclusive};
+
+use crate::boat_instructions::Target;
+use crate::boat_program::{has_return, Block, BoatExpr, BoatOp, Function, InlineHint, Program, Span, Statement};
+use crate::expr_optimizer::optimize_expr;
+use crate::expr_translator::{unrolled_exponent, MAX_UNROLLED_POWER};
+use crate::program_optimizer::recursive_program_functions;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    UndefinedVariable(String),
+    UndeclaredAssignment(String),
+    UndefinedFunction(String),
+    ArityMismatch { name: String, expected: RangeInclusive<usize>, found: usize },
+    ReturnOutsideFunction,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    DuplicateFunction(String),
+    NoReturnValue(String),
+    UnsupportedOperator(&'static str), // Operator needs extended math on the target
+    UnsupportedPower,                  // `**` without extended math and with a non-constant exponent
+    InlineNotSingleReturn(String),     // `inline function` with a body other than a single `return`
+    InlineRecursive(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Span,
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DiagnosticKind::*;
+        match self {
+            UndefinedVariable(name) => write!(f, "variable `{name}` is not declared"),
+            UndeclaredAssignment(name) => write!(f, "assignment to undeclared variable `{name}`, declare it with `let {name} = ...`"),
+            UndefinedFunction(name) => write!(f, "function `{name}` is not defined"),
+            ArityMismatch { name, expected, found } => {
+                if expected.start() == expected.end() {
+                    write!(f, "function `{name}` takes {} arguments but {found} were given", expected.start())
+                } else {
+                    write!(f, "function `{name}` takes {} to {} arguments but {found} were given", expected.start(), expected.end())
+                }
+            }
+            ReturnOutsideFunction => write!(f, "`return` outside of a function"),
+            BreakOutsideLoop => write!(f, "`break` outside of a loop"),
+            ContinueOutsideLoop => write!(f, "`continue` outside of a loop"),
+            DuplicateFunction(name) => write!(f, "function `{name}` is already defined"),
+            NoReturnValue(name) => write!(f, "function `{name}` does not return a value"),
+            UnsupportedOperator(op) => write!(f, "operator `{op}` needs a target with extended math"),
+            UnsupportedPower => write!(f, "`**` needs a target with extended math or a constant exponent from 0 to {MAX_UNROLLED_POWER}"),
+            InlineNotSingleReturn(name) => write!(f, "inline function `{name}` must have a body of a single `return`"),
+            InlineRecursive(name) => write!(f, "inline function `{name}` can not be recursive"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+struct Signature {
+    arity: RangeInclusive<usize>,
+    returns_value: bool,
+}
+
+struct Checker {
+    target: Target,
+    recursive: HashSet<String>,
+    functions: HashMap<String, Signature>, // Visible at the checked statement
+    declared: HashSet<String>,             // All functions of the program, their names have to be unique
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn report(&mut self, kind: DiagnosticKind, span: Span) {
+        self.diagnostics.push(Diagnostic { kind, span });
+    }
+
+    // as_value = the result is used
+    fn check_expr(&mut self, expr: &BoatExpr, vars: &HashSet<String>, as_value: bool) {
+        match expr {
+            BoatExpr::Value(..) => {}
+            BoatExpr::Var(name, span) => {
+                if !vars.contains(name) {
+                    self.report(DiagnosticKind::UndefinedVariable(name.clone()), *span);
+                }
+            }
+            BoatExpr::Function { name, args, span } => {
+                match self.functions.get(name) {
+                    Some(signature) => {
+                        let returns_value = signature.returns_value;
+                        if !signature.arity.contains(&args.len()) {
+                            let kind = DiagnosticKind::ArityMismatch { name: name.clone(), expected: signature.arity.clone(), found: args.len() };
+                            self.report(kind, *span);
+                        }
+                        if as_value && !returns_value {
+                            self.report(DiagnosticKind::NoReturnValue(name.clone()), *span);
+                        }
+                    }
+                    None => self.report(DiagnosticKind::UndefinedFunction(name.clone()), *span),
+                }
+                for arg in args {
+                    self.check_expr(arg, vars, true);
+                }
+            }
+            BoatExpr::BinOp { lhs, op, rhs, span } => {
+                if !self.target.extended_math && matches!(op, BoatOp::Mod | BoatOp::IntDiv | BoatOp::Pow) {
+                    // Constants are folded before translation, so only operations left after folding need the target
+                    match optimize_expr(expr.clone()) {
+                        BoatExpr::BinOp { op: BoatOp::Mod, .. } => self.report(DiagnosticKind::UnsupportedOperator("%"), *span),
+                        BoatExpr::BinOp { op: BoatOp::IntDiv, .. } => self.report(DiagnosticKind::UnsupportedOperator("~/"), *span),
+                        BoatExpr::BinOp { op: BoatOp::Pow, rhs, .. } if unrolled_exponent(&rhs).is_none() => self.report(DiagnosticKind::UnsupportedPower, *span),
+                        _ => {}
+                    }
+                }
+                self.check_expr(lhs, vars, true);
+                self.check_expr(rhs, vars, true);
+            }
+        }
+    }
+
+    // Variables declared in the block are visible until its end, function bodies see variables declared before them.
+    // So functions declared in the block are visible until its end too
+    fn check_block(&mut self, block: &Block, vars: &HashSet<String>, in_function: bool, in_loop: bool) {
+        self.check_statements(block, &mut vars.clone(), in_function, in_loop);
+        for s in block {
+            if let Statement::FunctionDefinition { name, .. } = s {
+                self.functions.remove(name);
+            }
+        }
+    }
+
+    // Declared variables are added to `vars`
+    fn check_statements(&mut self, block: &Block, vars: &mut HashSet<String>, in_function: bool, in_loop: bool) {
+        for s in block {
+            match s {
+                Statement::Assign { var_name, expr, .. } => {
+                    self.check_expr(expr, vars, true);
+                    vars.insert(var_name.clone());
+                }
+                Statement::Reassign { var_name, expr, span } => {
+                    if !vars.contains(var_name) {
+                        self.report(DiagnosticKind::UndeclaredAssignment(var_name.clone()), *span);
+                    }
+                    self.check_expr(expr, vars, true);
+                }
+                Statement::If { expr, block, else_block, .. } => {
+                    self.check_expr(expr, vars, true);
+                    self.check_block(block, vars, in_function, in_loop);
+                    if let Some(else_block) = else_block {
+                        self.check_block(else_block, vars, in_function, in_loop);
+                    }
+                }
+                Statement::While { expr, block, .. } => {
+                    self.check_expr(expr, vars, true);
+                    self.check_block(block, vars, in_function, true);
+                }
+                Statement::For { init, expr, step, block, .. } => {
+                    let mut loop_vars = vars.clone();
+                    self.check_statements(init, &mut loop_vars, in_function, in_loop);
+                    self.check_expr(expr, &loop_vars, true);
+                    self.check_block(block, &loop_vars, in_function, true);
+                    self.check_block(step, &loop_vars, in_function, true);
+                }
+                Statement::FunctionDefinition { name, arg_names, block, inline, span } => {
+                    if *inline == InlineHint::Always {
+                        if !matches!(block.as_slice(), [Statement::Return(..)]) {
+                            self.report(DiagnosticKind::InlineNotSingleReturn(name.clone()), *span);
+                        } else if self.recursive.contains(name) {
+                            self.report(DiagnosticKind::InlineRecursive(name.clone()), *span);
+                        }
+                    }
+                    let signature = Signature { arity: arg_names.len()..=arg_names.len(), returns_value: has_return(block) };
+                    if self.functions.insert(name.clone(), signature).is_some() || !self.declared.insert(name.clone()) {
+                        self.report(DiagnosticKind::DuplicateFunction(name.clone()), *span);
+                    }
+                    let mut function_vars = vars.clone();
+                    function_vars.extend(arg_names.iter().cloned());
+                    self.check_block(block, &function_vars, true, false);
+                }
+                Statement::Expr(expr) => self.check_expr(expr, vars, false),
+                Statement::Return(expr, span) => {
+                    if !in_function {
+                        self.report(DiagnosticKind::ReturnOutsideFunction, *span);
+                    }
+                    self.check_expr(expr, vars, true);
+                }
+                Statement::Break(span) => {
+                    if !in_loop {
+                        self.report(DiagnosticKind::BreakOutsideLoop, *span);
+                    }
+                }
+                Statement::Continue(span) => {
+                    if !in_loop {
+                        self.report(DiagnosticKind::ContinueOutsideLoop, *span);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reports undeclared variables, undefined functions, wrong argument counts, `return` outside of functions, `break` and `continue` outside of loops,
+/// duplicate function definitions, values of functions without `return`, operators the target does not support
+/// and `inline function`s that can not be inlined.
+pub fn check_program(program: &Program, target: &Target) -> Result<(), Vec<Diagnostic>> {
+    let functions = program.functions.iter().map(|(name, function)| {
+        let arity = match function {
+            Function::Predefined { arity, .. } => arity.clone(),
+            Function::InProgram { arg_names, .. } => arg_names.len()..=arg_names.len(),
+        };
+        (name.clone(), Signature { arity, returns_value: function.returns_value() })
+    }).collect();
+    let mut checker = Checker { target: *target, recursive: recursive_program_functions(&program.block), functions, declared: HashSet::new(), diagnostics: Vec::new() };
+    checker.check_block(&program.block, &HashSet::new(), false, false);
+    if checker.diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program_parser::parse_program;
+
+    fn check(source: &str) -> Vec<DiagnosticKind> {
+        let program = parse_program(source).unwrap();
+        check_program(&program, &Target::default()).err().unwrap_or_default().into_iter().map(|diagnostic| diagnostic.kind).collect()
+    }
+
+    #[test]
+    fn inline_hint_that_can_not_be_honored() {
+        let source = "print = out(1); { inline function f(a) { let b = a; return b; } inline function g(n) { return g(n - 1); } print(f(1)); }";
+        assert_eq!(check(source), [DiagnosticKind::InlineNotSingleReturn("f".into()), DiagnosticKind::InlineRecursive("g".into())]);
+        assert_eq!(check("print = out(1); { inline function f(a) { return a + 1; } print(f(1)); }"), []);
+    }
+
+    #[test]
+    fn functions_are_visible_until_the_end_of_their_block() {
+        let source = "print = out(1); input = in(2); { if (input()) { let t = 5; function f() { return t; } print(f()); } print(f()); }";
+        assert_eq!(check(source), [DiagnosticKind::UndefinedFunction("f".into())]);
+        let source = "print = out(1); { if (1) { function f() { return 1; } } if (1) { function f() { return 2; } } }";
+        assert_eq!(check(source), [DiagnosticKind::DuplicateFunction("f".into())]);
+    }
+
+    #[test]
+    fn extended_math_is_checked_after_folding() {
+        let source = "print = out(1); input = in(2); { let a = input(); print(a ** (1 + 1)); print(2 ** 0.5); print(7 % 3 .. 7 ~/ 2); }";
+        assert_eq!(check(source), []);
+        let source = "print = out(1); input = in(2); { let a = input(); print(a ** (a + 1)); print(a % 2); print(a ~/ 2); }";
+        assert_eq!(check(source), [DiagnosticKind::UnsupportedPower, DiagnosticKind::UnsupportedOperator("%"), DiagnosticKind::UnsupportedOperator("~/")]);
+    }
+
+    #[test]
+    fn undefined_variable() {
+        assert_eq!(check("print = out(1); { let a = 1; print(a + b); }"), [DiagnosticKind::UndefinedVariable("b".into())]);
+    }
+
+    #[test]
+    fn wrong_argument_count() {
+        let source = "print = out(1); { function f(a, b) { return a + b; } print(f(1)); print(1, 2); }";
+        assert_eq!(check(source), [
+            DiagnosticKind::ArityMismatch { name: "f".into(), expected: 2..=2, found: 1 },
+            DiagnosticKind::ArityMismatch { name: "print".into(), expected: 1..=1, found: 2 },
+        ]);
+    }
+
+    #[test]
+    fn return_outside_function() {
+        assert_eq!(check("print = out(1); { print(1); if (1) { return 2; } }"), [DiagnosticKind::ReturnOutsideFunction]);
+    }
+
+    #[test]
+    fn duplicate_definitions() {
+        let source = "print = out(1); { function f() { return 1; } function f() { return 2; } function print(x) { return x; } }";
+        assert_eq!(check(source), [DiagnosticKind::DuplicateFunction("f".into()), DiagnosticKind::DuplicateFunction("print".into())]);
+    }
+
+    #[test]
+    fn assignment_to_undeclared_variable() {
+        assert_eq!(check("print = out(1); { let a = 1; a = 2; b = 3; }"), [DiagnosticKind::UndeclaredAssignment("b".into())]);
+        assert_eq!(check("print = out(1); { if (1) { let a = 1; } a = 2; }"), [DiagnosticKind::UndeclaredAssignment("a".into())]);
+    }
+}
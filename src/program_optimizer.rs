@@ -1,42 +1,494 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use crate::boat_program::{Block, Program, Statement};
+use crate::boat_program::{has_return, Block, BoatExpr, BoatOp, InlineHint, Program, Statement};
+use crate::expr_optimizer::optimize_expr;
 
-fn optimize_block_reassigns(block: &mut Block, current_vars: &mut HashSet<String>) {
+// Replaces every expression in the block with `f` of it
+fn map_block_exprs(block: &mut Block, f: &dyn Fn(BoatExpr) -> BoatExpr) {
+    let map = |expr: &mut BoatExpr| {
+        let placeholder = BoatExpr::Value(String::new(), expr.span());
+        *expr = f(std::mem::replace(expr, placeholder));
+    };
     for s in block {
         match s {
-            Statement::Assign { ref var_name, ref expr } => {
-                if current_vars.contains(var_name.as_str()) {
-                    // let _ = std::mem::replace(s, Statement::Assign { var_name: var_name.clone(), expr: expr.clone() });
-                    *s = Statement::Reassign { var_name: var_name.clone(), expr: expr.clone() };
-                } else {
-                    current_vars.insert(var_name.clone());
+            Statement::Assign { expr, .. } | Statement::Reassign { expr, .. } | Statement::Expr(expr) | Statement::Return(expr, _) => map(expr),
+            Statement::If { expr, block, else_block, .. } => {
+                map(expr);
+                map_block_exprs(block, f);
+                if let Some(else_block) = else_block {
+                    map_block_exprs(else_block, f);
                 }
             }
-            Statement::If { expr, block, else_block } => {
-                optimize_block_reassigns(block, current_vars);
+            Statement::While { expr, block, .. } => {
+                map(expr);
+                map_block_exprs(block, f);
+            }
+            Statement::For { init, expr, step, block, .. } => {
+                map_block_exprs(init, f);
+                map(expr);
+                map_block_exprs(step, f);
+                map_block_exprs(block, f);
+            }
+            Statement::FunctionDefinition { block, .. } => map_block_exprs(block, f),
+            Statement::Break(_) | Statement::Continue(_) => {}
+        }
+    }
+}
+
+/// Runs `expr_optimizer` on every expression of the program
+pub fn optimize_exprs(program: &mut Program) {
+    map_block_exprs(&mut program.block, &optimize_expr);
+}
+
+// Value checked by `c` for a constant condition, None when it is not known statically
+fn const_condition(expr: &BoatExpr) -> Option<bool> {
+    match expr {
+        BoatExpr::Value(value, _) => value.parse::<f32>().ok().map(|value| value != 0.),
+        _ => None,
+    }
+}
+
+// Statements after it in the block are never executed
+fn leaves_block(s: &Statement) -> bool {
+    matches!(s, Statement::Return(..) | Statement::Break(_) | Statement::Continue(_))
+}
+
+fn remove_dead_statements(block: &mut Block) {
+    let mut result = Block::new();
+    // Set after `return`, `break` and `continue`
+    let mut dead = false;
+    // Functions are visible only after their definition and until the end of its block,
+    // so definitions in removed code can not be called either
+    for s in std::mem::take(block) {
+        if dead {
+            continue;
+        }
+        match s {
+            Statement::If { expr, block, else_block, span } => match const_condition(&expr) {
+                Some(taken) => {
+                    let else_block = else_block.unwrap_or_default();
+                    let mut live = if taken { block } else { else_block };
+                    remove_dead_statements(&mut live);
+                    dead = live.last().is_some_and(leaves_block);
+                    if live.iter().any(|s| matches!(s, Statement::Assign { .. })) {
+                        // Variables declared in the branch have to be dropped at its end, so it stays a block
+                        result.push(Statement::If { expr: BoatExpr::Value("1".to_owned(), expr.span()), block: live, else_block: None, span });
+                    } else {
+                        result.extend(live);
+                    }
+                }
+                None => {
+                    let mut block = block;
+                    let mut else_block = else_block;
+                    remove_dead_statements(&mut block);
+                    if let Some(else_block) = else_block.as_mut() {
+                        remove_dead_statements(else_block);
+                    }
+                    result.push(Statement::If { expr, block, else_block, span });
+                }
+            },
+            Statement::While { expr, .. } if const_condition(&expr) == Some(false) => {}
+            Statement::While { expr, mut block, span } => {
+                remove_dead_statements(&mut block);
+                result.push(Statement::While { expr, block, span });
+            }
+            Statement::For { init, expr, step, mut block, span } => {
+                remove_dead_statements(&mut block);
+                result.push(Statement::For { init, expr, step, block, span });
+            }
+            Statement::FunctionDefinition { name, arg_names, mut block, inline, span } => {
+                let returns_value = has_return(&block);
+                remove_dead_statements(&mut block);
+                // Calls are still used as values when every `return` was removed, so the function returns 0 like at the end of its body
+                if returns_value && !has_return(&block) {
+                    block.push(Statement::Return(BoatExpr::Value("0".to_owned(), span), span));
+                }
+                result.push(Statement::FunctionDefinition { name, arg_names, block, inline, span });
+            }
+            s if leaves_block(&s) => {
+                dead = true;
+                result.push(s);
+            }
+            _ => result.push(s),
+        }
+    }
+    *block = result;
+}
+
+fn collect_calls(expr: &BoatExpr, calls: &mut HashSet<String>) {
+    match expr {
+        BoatExpr::Function { name, args, .. } => {
+            calls.insert(name.clone());
+            for arg in args {
+                collect_calls(arg, calls);
+            }
+        }
+        BoatExpr::BinOp { lhs, rhs, .. } => {
+            collect_calls(lhs, calls);
+            collect_calls(rhs, calls);
+        }
+        _ => {}
+    }
+}
+
+// calls = functions called from the block outside of nested definitions, graph = calls of every defined function
+fn collect_block_calls(block: &Block, calls: &mut HashSet<String>, graph: &mut HashMap<String, HashSet<String>>) {
+    for s in block {
+        match s {
+            Statement::Assign { expr, .. } | Statement::Reassign { expr, .. } | Statement::Expr(expr) | Statement::Return(expr, _) => collect_calls(expr, calls),
+            Statement::If { expr, block, else_block, .. } => {
+                collect_calls(expr, calls);
+                collect_block_calls(block, calls, graph);
+                if let Some(else_block) = else_block {
+                    collect_block_calls(else_block, calls, graph);
+                }
+            }
+            Statement::While { expr, block, .. } => {
+                collect_calls(expr, calls);
+                collect_block_calls(block, calls, graph);
+            }
+            Statement::For { init, expr, step, block, .. } => {
+                collect_block_calls(init, calls, graph);
+                collect_calls(expr, calls);
+                collect_block_calls(step, calls, graph);
+                collect_block_calls(block, calls, graph);
+            }
+            Statement::FunctionDefinition { name, block, .. } => {
+                let mut function_calls = HashSet::<String>::new();
+                collect_block_calls(block, &mut function_calls, graph);
+                graph.insert(name.clone(), function_calls);
+            }
+            Statement::Break(_) | Statement::Continue(_) => {}
+        }
+    }
+}
+
+fn remove_unused_functions(block: &mut Block, used: &HashSet<String>) {
+    let mut result = Block::new();
+    for mut s in std::mem::take(block) {
+        match &mut s {
+            // Nested definitions are visible only inside of the body
+            Statement::FunctionDefinition { name, .. } if !used.contains(name) => continue,
+            Statement::FunctionDefinition { block, .. } | Statement::While { block, .. } | Statement::For { block, .. } => remove_unused_functions(block, used),
+            Statement::If { block, else_block, .. } => {
+                remove_unused_functions(block, used);
                 if let Some(else_block) = else_block {
-                    optimize_block_reassigns(else_block, current_vars);
+                    remove_unused_functions(else_block, used);
                 }
             }
-            Statement::While { expr, block } => {
-                optimize_block_reassigns(block, current_vars);
+            _ => {}
+        }
+        result.push(s);
+    }
+    *block = result;
+}
+
+/// Removes branches of constant conditions, statements after `return`, `break` and `continue`
+/// and functions not reachable from the program body
+pub fn eliminate_dead_code(program: &mut Program) {
+    remove_dead_statements(&mut program.block);
+    let mut calls = HashSet::<String>::new();
+    let mut graph = HashMap::<String, HashSet<String>>::new();
+    collect_block_calls(&program.block, &mut calls, &mut graph);
+    let mut used = HashSet::<String>::new();
+    let mut pending = calls.into_iter().collect::<Vec<String>>();
+    while let Some(name) = pending.pop() {
+        if let Some(function_calls) = graph.get(&name).filter(|_| used.insert(name.clone())) {
+            pending.extend(function_calls.iter().cloned());
+        }
+    }
+    remove_unused_functions(&mut program.block, &used);
+}
+
+/// Calls to functions with a larger body are only inlined for `inline function`
+const MAX_INLINE_SIZE: usize = 8;
+
+fn expr_size(expr: &BoatExpr) -> usize {
+    match expr {
+        BoatExpr::Function { args, .. } => 1 + args.iter().map(expr_size).sum::<usize>(),
+        BoatExpr::BinOp { lhs, rhs, .. } => 1 + expr_size(lhs) + expr_size(rhs),
+        _ => 1,
+    }
+}
+
+fn has_calls(expr: &BoatExpr) -> bool {
+    let mut calls = HashSet::<String>::new();
+    collect_calls(expr, &mut calls);
+    !calls.is_empty()
+}
+
+fn var_uses(expr: &BoatExpr, name: &str) -> usize {
+    match expr {
+        BoatExpr::Var(var, _) => (var == name) as usize,
+        BoatExpr::Value(..) => 0,
+        BoatExpr::Function { args, .. } => args.iter().map(|arg| var_uses(arg, name)).sum(),
+        BoatExpr::BinOp { lhs, rhs, .. } => var_uses(lhs, name) + var_uses(rhs, name),
+    }
+}
+
+// Uses in right operands of `&&` and `||`, which are not always evaluated
+fn conditional_uses(expr: &BoatExpr, name: &str) -> usize {
+    match expr {
+        BoatExpr::BinOp { lhs, op: BoatOp::And | BoatOp::Or, rhs, .. } => conditional_uses(lhs, name) + var_uses(rhs, name),
+        BoatExpr::BinOp { lhs, rhs, .. } => conditional_uses(lhs, name) + conditional_uses(rhs, name),
+        BoatExpr::Function { args, .. } => args.iter().map(|arg| conditional_uses(arg, name)).sum(),
+        _ => 0,
+    }
+}
+
+// Functions calling themselves directly or through other functions
+fn recursive_functions(graph: &HashMap<String, HashSet<String>>) -> HashSet<String> {
+    graph.keys().filter(|name| {
+        let mut visited = HashSet::<&String>::new();
+        let mut pending = graph[*name].iter().collect::<Vec<&String>>();
+        while let Some(callee) = pending.pop() {
+            if callee == *name {
+                return true;
+            }
+            if visited.insert(callee) {
+                pending.extend(graph.get(callee).into_iter().flatten());
             }
-            Statement::FunctionDefinition { name, arg_names, block } => {
-                for name in arg_names.iter() {
-                    current_vars.insert(name.clone());
+        }
+        false
+    }).cloned().collect()
+}
+
+struct InlineFunction {
+    arg_names: Vec<String>,
+    body: BoatExpr,
+}
+
+// Functions with a body of a single `return`
+fn collect_inline_functions(block: &Block, recursive: &HashSet<String>, inline_functions: &mut HashMap<String, InlineFunction>) {
+    for s in block {
+        match s {
+            Statement::FunctionDefinition { name, arg_names, block, inline, .. } => {
+                if let [Statement::Return(body, _)] = block.as_slice() {
+                    let small = *inline == InlineHint::Always || (*inline == InlineHint::Auto && expr_size(body) <= MAX_INLINE_SIZE);
+                    if small && !recursive.contains(name) {
+                        inline_functions.insert(name.clone(), InlineFunction { arg_names: arg_names.clone(), body: body.clone() });
+                    }
                 }
-                optimize_block_reassigns(block, current_vars);
-                for name in arg_names.iter() {
-                    current_vars.remove(name.as_str());
+                collect_inline_functions(block, recursive, inline_functions);
+            }
+            Statement::If { block, else_block, .. } => {
+                collect_inline_functions(block, recursive, inline_functions);
+                if let Some(else_block) = else_block {
+                    collect_inline_functions(else_block, recursive, inline_functions);
                 }
             }
+            Statement::While { block, .. } | Statement::For { block, .. } => collect_inline_functions(block, recursive, inline_functions),
             _ => {}
         }
     }
 }
 
-pub fn optimize_reassigns(program: &mut Program) {
-    let mut vars = HashSet::<String>::new();
-    optimize_block_reassigns(&mut program.block, &mut vars);
-}
\ No newline at end of file
+fn substitute(expr: &BoatExpr, params: &HashMap<&str, &BoatExpr>) -> BoatExpr {
+    match expr {
+        BoatExpr::Var(name, _) => params.get(name.as_str()).map_or_else(|| expr.clone(), |arg| (*arg).clone()),
+        BoatExpr::Value(..) => expr.clone(),
+        BoatExpr::Function { name, args, span } => {
+            BoatExpr::Function { name: name.clone(), args: args.iter().map(|arg| substitute(arg, params)).collect(), span: *span }
+        }
+        BoatExpr::BinOp { lhs, op, rhs, span } => {
+            BoatExpr::BinOp { lhs: Box::new(substitute(lhs, params)), op: op.clone(), rhs: Box::new(substitute(rhs, params)), span: *span }
+        }
+    }
+}
+
+// Arguments with calls must be evaluated exactly once, unconditionally and not reordered with other calls.
+// Calls of the body may change variables, so then only constants are substituted as they can not be read later
+fn can_substitute(function: &InlineFunction, args: &[BoatExpr]) -> bool {
+    if has_calls(&function.body) {
+        return args.iter().all(|arg| matches!(arg, BoatExpr::Value(..)));
+    }
+    let impure = args.iter().zip(&function.arg_names).filter(|(arg, _)| has_calls(arg)).collect::<Vec<_>>();
+    match impure.as_slice() {
+        [] => true,
+        [(_, name)] => var_uses(&function.body, name) == 1 && conditional_uses(&function.body, name) == 0,
+        _ => false,
+    }
+}
+
+fn inline_expr(expr: BoatExpr, inline_functions: &HashMap<String, InlineFunction>) -> BoatExpr {
+    match expr {
+        BoatExpr::Function { name, args, span } => {
+            let args = args.into_iter().map(|arg| inline_expr(arg, inline_functions)).collect::<Vec<BoatExpr>>();
+            match inline_functions.get(&name) {
+                Some(function) if can_substitute(function, &args) => {
+                    let params = function.arg_names.iter().map(String::as_str).zip(&args).collect();
+                    // Body may call other inlined functions, it can not call itself
+                    inline_expr(substitute(&function.body, &params), inline_functions)
+                }
+                _ => BoatExpr::Function { name, args, span },
+            }
+        }
+        BoatExpr::BinOp { lhs, op, rhs, span } => BoatExpr::BinOp {
+            lhs: Box::new(inline_expr(*lhs, inline_functions)),
+            op,
+            rhs: Box::new(inline_expr(*rhs, inline_functions)),
+            span,
+        },
+        value_or_var => value_or_var,
+    }
+}
+
+/// Functions of the block calling themselves directly or through other functions
+pub(crate) fn recursive_program_functions(block: &Block) -> HashSet<String> {
+    let mut graph = HashMap::<String, HashSet<String>>::new();
+    collect_block_calls(block, &mut HashSet::new(), &mut graph);
+    recursive_functions(&graph)
+}
+
+/// Replaces calls to small functions returning a single expression with the expression.
+/// Recursive functions and functions marked `noinline` are never inlined
+pub fn inline_functions(program: &mut Program) {
+    let recursive = recursive_program_functions(&program.block);
+    let mut inline_functions = HashMap::<String, InlineFunction>::new();
+    collect_inline_functions(&program.block, &recursive, &mut inline_functions);
+    map_block_exprs(&mut program.block, &|expr| inline_expr(expr, &inline_functions));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program_parser::parse_program;
+
+    // Constants of expressions left in the block
+    fn collect_block_values(block: &Block, values: &mut Vec<String>) {
+        for s in block {
+            match s {
+                Statement::Assign { expr, .. } | Statement::Reassign { expr, .. } | Statement::Expr(expr) | Statement::Return(expr, _) => collect_values(expr, values),
+                Statement::If { expr, block, else_block, .. } => {
+                    collect_values(expr, values);
+                    collect_block_values(block, values);
+                    collect_block_values(else_block.as_ref().unwrap_or(&Block::new()), values);
+                }
+                Statement::While { expr, block, .. } => {
+                    collect_values(expr, values);
+                    collect_block_values(block, values);
+                }
+                Statement::For { init, expr, step, block, .. } => {
+                    collect_block_values(init, values);
+                    collect_values(expr, values);
+                    collect_block_values(step, values);
+                    collect_block_values(block, values);
+                }
+                Statement::FunctionDefinition { block, .. } => collect_block_values(block, values),
+                Statement::Break(_) | Statement::Continue(_) => {}
+            }
+        }
+    }
+
+    fn collect_values(expr: &BoatExpr, values: &mut Vec<String>) {
+        match expr {
+            BoatExpr::Value(value, _) => values.push(value.clone()),
+            BoatExpr::Var(..) => {}
+            BoatExpr::Function { args, .. } => args.iter().for_each(|arg| collect_values(arg, values)),
+            BoatExpr::BinOp { lhs, rhs, .. } => {
+                collect_values(lhs, values);
+                collect_values(rhs, values);
+            }
+        }
+    }
+
+    // Functions defined in the block
+    fn collect_functions(block: &Block, functions: &mut Vec<String>) {
+        for s in block {
+            match s {
+                Statement::FunctionDefinition { name, block, .. } => {
+                    functions.push(name.clone());
+                    collect_functions(block, functions);
+                }
+                Statement::If { block, else_block, .. } => {
+                    collect_functions(block, functions);
+                    collect_functions(else_block.as_ref().unwrap_or(&Block::new()), functions);
+                }
+                Statement::While { block, .. } | Statement::For { block, .. } => collect_functions(block, functions),
+                _ => {}
+            }
+        }
+    }
+
+    fn without_dead_code(source: &str) -> (Vec<String>, Vec<String>) {
+        let mut program = parse_program(source).unwrap();
+        eliminate_dead_code(&mut program);
+        let (mut values, mut functions) = (Vec::new(), Vec::new());
+        collect_block_values(&program.block, &mut values);
+        collect_functions(&program.block, &mut functions);
+        (values, functions)
+    }
+
+    #[test]
+    fn constant_conditions() {
+        let source = "print = out(1); {
+            if (0) { print(\"if 0\"); } else { print(\"else 0\"); }
+            if (1) { print(\"if 1\"); } else { print(\"else 1\"); }
+            while (0) { print(\"while 0\"); }
+            print(\"end\");
+        }";
+        assert_eq!(without_dead_code(source).0, ["else 0", "if 1", "end"]);
+    }
+
+    #[test]
+    fn code_after_return_and_break() {
+        let source = "print = out(1); {
+            function f() {
+                return \"returned\";
+                print(\"after return\");
+            }
+            while (1) {
+                print(f());
+                break;
+                print(\"after break\");
+            }
+            for (;;) {
+                continue;
+                print(\"after continue\");
+            }
+        }";
+        let (values, _) = without_dead_code(source);
+        assert!(values.contains(&"returned".to_owned()));
+        assert!(!values.iter().any(|value| value.starts_with("after")), "{values:?}");
+    }
+
+    #[test]
+    fn unused_functions() {
+        let source = "print = out(1); {
+            function helper(x) { return x + 1; }
+            function unused(x) { return helper(x); }
+            function used(x) {
+                function nested_unused() { return 1; }
+                return x * 2;
+            }
+            if (0) {
+                function dead() { return 1; }
+                print(dead());
+            }
+            print(used(1));
+        }";
+        assert_eq!(without_dead_code(source).1, ["used"]);
+    }
+
+    // Functions still called from the program body after inlining
+    fn calls_after_inlining(source: &str) -> Vec<String> {
+        let mut program = parse_program(source).unwrap();
+        inline_functions(&mut program);
+        let mut calls = HashSet::<String>::new();
+        collect_block_calls(&program.block, &mut calls, &mut HashMap::new());
+        let mut calls = calls.into_iter().collect::<Vec<String>>();
+        calls.sort();
+        calls
+    }
+
+    #[test]
+    fn inlining() {
+        let source = "print = out(1); {
+            function double(x) { return x * 2; }
+            noinline function triple(x) { return x * 3; }
+            function countdown(n) { return countdown(n - 1); }
+            print(double(1) + triple(1) + countdown(1));
+        }";
+        assert_eq!(calls_after_inlining(source), ["countdown", "print", "triple"]);
+    }
+}
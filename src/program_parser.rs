@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use pest::{iterators::Pairs, Parser};
-use crate::{boat_instructions::{BoatArg, BoatCmd, BoatIns}, boat_program::{Block, Function, Program, Statement}, expr_parser::parse_pairs};
+use pest::{error::{Error, ErrorVariant}, iterators::Pairs, Parser};
+use crate::{boat_instructions::{BoatArg, BoatCmd, BoatIns}, boat_program::{Block, BoatExpr, Function, InlineHint, Program, Span, Statement}, expr_parser::parse_pairs};
 
 
 
@@ -15,6 +15,7 @@ pub enum PinType {
 pub struct PinDefinition {
     pub name: String,
     pub pin: PinType,
+    pub span: Span,
 }
 
 #[derive(pest_derive::Parser)]
@@ -23,6 +24,7 @@ pub struct ProgramParser;
 
 pub fn parse_definitions(pairs: Pairs<Rule>) -> Vec<PinDefinition> {
     pairs.into_iter().map(|pair| {
+        let span = Span::from(pair.as_span());
         let mut inner = pair.into_inner();
         let name = inner.next().unwrap().as_str().to_owned();
         let f = inner.next().unwrap();
@@ -33,19 +35,21 @@ pub fn parse_definitions(pairs: Pairs<Rule>) -> Vec<PinDefinition> {
         };
         let inner = f.into_inner().next().unwrap();
         let pin = inner.as_str().parse::<u32>().unwrap();
-        PinDefinition { name, pin: tpe(pin) }
+        PinDefinition { name, pin: tpe(pin), span }
     }).collect()
 }
 
 pub fn parse_block(pairs: Pairs<Rule>) -> Block {
     pairs.into_iter().map(|pair| {
+        let span = Span::from(pair.as_span());
         match pair.as_rule() {
             Rule::r#if => {
                 let mut inner = pair.into_inner();
                 Statement::If {
                     expr: parse_pairs(inner.next().unwrap().into_inner()),
                     block: parse_block(inner.next().unwrap().into_inner()),
-                    else_block: inner.next().map(|pair| parse_block(pair.into_inner()))
+                    else_block: inner.next().map(|pair| parse_block(pair.into_inner())),
+                    span,
                 }
             },
             Rule::r#while => {
@@ -53,24 +57,59 @@ pub fn parse_block(pairs: Pairs<Rule>) -> Block {
                 Statement::While {
                     expr: parse_pairs(inner.next().unwrap().into_inner()),
                     block: parse_block(inner.next().unwrap().into_inner()),
+                    span,
                 }
             },
-            Rule::assign => {
+            Rule::r#for => {
+                let mut inner = pair.into_inner().peekable();
+                let init = parse_block(inner.next().unwrap().into_inner());
+                let expr = match inner.next_if(|pair| pair.as_rule() == Rule::expr) {
+                    Some(expr) => parse_pairs(expr.into_inner()),
+                    None => BoatExpr::Value("1".to_owned(), span),
+                };
+                Statement::For {
+                    init,
+                    expr,
+                    step: parse_block(inner.next().unwrap().into_inner()),
+                    block: parse_block(inner.next().unwrap().into_inner()),
+                    span,
+                }
+            },
+            Rule::r#let | Rule::let_clause => {
                 let mut inner = pair.into_inner();
                 Statement::Assign {
                     var_name: inner.next().unwrap().as_str().to_owned(),
                     expr: parse_pairs(inner.next().unwrap().into_inner()),
+                    span,
+                }
+            },
+            Rule::assign | Rule::assign_clause => {
+                let mut inner = pair.into_inner();
+                Statement::Reassign {
+                    var_name: inner.next().unwrap().as_str().to_owned(),
+                    expr: parse_pairs(inner.next().unwrap().into_inner()),
+                    span,
                 }
             },
             Rule::r#return => {
                 let mut inner = pair.into_inner();
-                Statement::Return(parse_pairs(inner.next().unwrap().into_inner()))
+                Statement::Return(parse_pairs(inner.next().unwrap().into_inner()), span)
             }
             Rule::expr => {
                 Statement::Expr(parse_pairs(pair.into_inner()))
             }
+            Rule::r#break => Statement::Break(span),
+            Rule::r#continue => Statement::Continue(span),
             Rule::function_definition => {
-                let mut inner = pair.into_inner();
+                let mut inner = pair.into_inner().peekable();
+                let inline = match inner.peek().unwrap().as_rule() {
+                    Rule::inline => InlineHint::Always,
+                    Rule::noinline => InlineHint::Never,
+                    _ => InlineHint::Auto,
+                };
+                if inline != InlineHint::Auto {
+                    inner.next();
+                }
                 let name = inner.next().unwrap().as_str().to_owned();
                 let mut arg = inner.next().unwrap();
                 let mut args = Vec::<String>::new();
@@ -78,23 +117,57 @@ pub fn parse_block(pairs: Pairs<Rule>) -> Block {
                     args.push(arg.as_str().to_owned());
                     arg = inner.next().unwrap();
                 }
-                Statement::FunctionDefinition { name, arg_names: args,  block: parse_block(arg.into_inner()) }
+                Statement::FunctionDefinition { name, arg_names: args,  block: parse_block(arg.into_inner()), inline, span }
             }
             _ => unreachable!()
         }
     }).collect()
 }
 
-pub fn parse_program(s: &str) -> Result<Program, pest::error::Error<Rule>> {
+pub fn parse_program(s: &str) -> Result<Program, Box<pest::error::Error<Rule>>> {
     let mut functions = HashMap::<String, Function>::new();
     let mut parsed = ProgramParser::parse(Rule::program, s)?;
     let mut program = parsed.next().unwrap().into_inner();
     let definitions_pairs = program.next().unwrap().into_inner();
     let main_block_pairs = program.next().unwrap().into_inner();
+    functions.insert("sleep".to_owned(), Function::Predefined { arity: 1..=1, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::Sleep, args } ]
+    }) });
+    functions.insert("display".to_owned(), Function::Predefined { arity: 2..=2, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::Display, args } ]
+    }) });
+    functions.insert("dclear".to_owned(), Function::Predefined { arity: 0..=0, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::DisplayClear, args } ]
+    }) });
+    functions.insert("out".to_owned(), Function::Predefined { arity: 2..=2, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::Output, args } ]
+    }) });
+    functions.insert("in".to_owned(), Function::Predefined { arity: 1..=2, translator: Box::new(|args: Vec<BoatArg>| {
+        let cmd = if args.len() == 2 { BoatCmd::InputAsync } else { BoatCmd::Input };
+        vec![ BoatIns { cmd, args } ]
+    }) });
+    functions.insert("clear".to_owned(), Function::Predefined { arity: 1..=1, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::Clear, args } ]
+    }) });
+    functions.insert("store".to_owned(), Function::Predefined { arity: 2..=2, translator: Box::new(|args: Vec<BoatArg>| {
+        vec![ BoatIns { cmd: BoatCmd::Store, args } ]
+    }) });
+    // Pins are defined after the builtins so they can shadow them
     let pin_definitions = parse_definitions(definitions_pairs);
+    let mut pin_names = HashSet::<String>::new();
     for pin_def in pin_definitions {
+        if !pin_names.insert(pin_def.name.clone()) {
+            let span = pest::Span::new(s, pin_def.span.start, pin_def.span.end).unwrap();
+            let message = format!("pin `{}` is already defined", pin_def.name);
+            return Err(Box::new(Error::new_from_span(ErrorVariant::CustomError { message }, span)));
+        }
         let pin_num = pin_def.pin;
-        functions.insert(pin_def.name, Function::Predefined { translator: Box::new(move |mut args: Vec<BoatArg>| {
+        // Input pins take an optional timeout, output pins take the value
+        let arity = match pin_num {
+            PinType::In(_) => 0..=1,
+            PinType::Out(_) => 1..=1,
+        };
+        functions.insert(pin_def.name, Function::Predefined { arity, translator: Box::new(move |mut args: Vec<BoatArg>| {
             let (mut tpe, num) = match pin_num {
                 PinType::In(i) => (BoatCmd::Input, i),
                 PinType::Out(i) => (BoatCmd::Output, i),
@@ -107,27 +180,6 @@ pub fn parse_program(s: &str) -> Result<Program, pest::error::Error<Rule>> {
             vec![ BoatIns { cmd: tpe, args } ]
         }) });
     }
-    functions.insert("sleep".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Sleep, args } ]
-    }) });
-    functions.insert("display".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Display, args } ]
-    }) });
-    functions.insert("dclear".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::DisplayClear, args } ]
-    }) });
-    functions.insert("out".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Output, args } ]
-    }) });
-    functions.insert("in".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Input, args } ]
-    }) });
-    functions.insert("clear".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Clear, args } ]
-    }) });
-    functions.insert("store".to_owned(), Function::Predefined { translator: Box::new(|args: Vec<BoatArg>| {
-        vec![ BoatIns { cmd: BoatCmd::Store, args } ]
-    }) });
     let block = parse_block(main_block_pairs);
     Ok(Program { functions, block })
 }
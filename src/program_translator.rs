@@ -1,106 +1,213 @@
-use std::collections::HashSet;
+use crate::boat_instructions::{BoatIns, BoatArg, BoatCmd, Label, Target};
+use crate::expr_translator::{translate_condition, translate_expr};
+use crate::boat_program::{has_return, Block, BoatExpr, Function, Functions, Program, Span, Statement};
+use crate::linker::Code;
 
-use crate::boat_instructions::{BoatIns, BoatArg, BoatCmd};
-use crate::expr_translator::translate_expr;
-use crate::boat_program::{Block, BoatExpr, Function, Functions, Program, Statement};
+// Function whose body is being translated
+struct CurrentFunction {
+    name: String,
+    arg_names: Vec<String>,
+    begin: Label,
+}
+
+// Loop whose body is being translated
+struct Loop {
+    break_label: Label,
+    continue_label: Label,
+    scopes: usize, // Number of scopes outside of the body, the rest are dropped by `break` and `continue`
+}
+
+// Function or program body being translated
+struct Frame {
+    epilogue: Label, // Where `return` jumps to
+    function: Option<CurrentFunction>,
+    // Variables declared with `let` in the enclosing blocks of the body, innermost last
+    scopes: Vec<Vec<(String, Span)>>,
+    loops: Vec<Loop>, // Innermost last
+}
 
-// current_ins_i = index of last instruction + 1
-fn translate_statement(s: Statement, instruction_index: &mut u32, functions: &mut Functions, labeled_lines: &mut HashSet<u32>) -> Vec<BoatIns> {
+fn drop_vars(vars: &[(String, Span)], code: &mut Code) {
+    for (name, span) in vars.iter().rev() {
+        code.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const(name.clone())] }, *span);
+    }
+}
+
+// Leaving blocks drops their variables, innermost first
+fn drop_scopes(scopes: &[Vec<(String, Span)>], code: &mut Code) {
+    for vars in scopes.iter().rev() {
+        drop_vars(vars, code);
+    }
+}
+
+// Loop body with `break` and `continue` jumping to the labels
+fn translate_loop_body(block: Block, break_label: Label, continue_label: Label, code: &mut Code, functions: &mut Functions, frame: &mut Frame, target: &Target) {
+    frame.loops.push(Loop { break_label, continue_label, scopes: frame.scopes.len() });
+    translate_block(block, code, functions, frame, target);
+    frame.loops.pop();
+}
+
+// `return name(args)` in the function itself reassigns the arguments and jumps to the function start
+fn translate_tail_call(args: Vec<BoatExpr>, frame: &Frame, code: &mut Code, functions: &Functions, target: &Target, span: Span) {
+    let function = frame.function.as_ref().unwrap();
+    // Values are computed before any argument changes, as they can depend on them
+    let mut consts = Vec::<(String, BoatArg)>::new();
+    let mut stacked = Vec::<String>::new();
+    for (arg, name) in args.into_iter().zip(function.arg_names.iter()).rev() {
+        match translate_expr(arg, code, functions, target) {
+            BoatArg::Const(value) => consts.push((name.clone(), BoatArg::Const(value))),
+            BoatArg::FromStack => stacked.push(name.clone()),
+            arg => {
+                code.push(BoatIns { cmd: BoatCmd::Push, args: vec![arg] }, span);
+                stacked.push(name.clone());
+            }
+        }
+    }
+    // Variables are declared again by the body
+    drop_scopes(&frame.scopes, code);
+    for name in stacked.into_iter().rev() {
+        code.push(BoatIns { cmd: BoatCmd::KVReSet, args: vec![BoatArg::Const(name), BoatArg::FromStack] }, span);
+    }
+    for (name, value) in consts {
+        code.push(BoatIns { cmd: BoatCmd::KVReSet, args: vec![BoatArg::Const(name), value] }, span);
+    }
+    code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(function.begin)] }, span);
+}
+
+fn translate_statement(s: Statement, code: &mut Code, functions: &mut Functions, frame: &mut Frame, target: &Target) {
+    let span = s.span();
     match s {
-        Statement::Reassign { var_name, expr } => {
-            let mut instructions = Vec::<BoatIns>::new();
-            let arg = translate_expr(expr, instruction_index, &mut instructions, functions, labeled_lines);
-            instructions.push(BoatIns { cmd: BoatCmd::KVReSet, args: vec![BoatArg::Const(var_name), arg] });
-            *instruction_index += 1;
-            instructions
+        Statement::Reassign { var_name, expr, .. } => {
+            let arg = translate_expr(expr, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::KVReSet, args: vec![BoatArg::Const(var_name), arg] }, span);
         }
-        Statement::Assign { var_name, expr } => {
-            let mut instructions = Vec::<BoatIns>::new();
-            let arg = translate_expr(expr, instruction_index, &mut instructions, functions, labeled_lines);
-            // instructions.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const(var_name.clone())] });
-            // *instruction_index += 1;
-            instructions.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const(var_name), arg] });
-            *instruction_index += 1;
-            instructions
+        Statement::Assign { var_name, expr, .. } => {
+            let arg = translate_expr(expr, code, functions, target);
+            code.push(BoatIns { cmd: BoatCmd::KVSet, args: vec![BoatArg::Const(var_name.clone()), arg] }, span);
+            frame.scopes.last_mut().unwrap().push((var_name, span));
         }
-        Statement::If { expr, block, else_block } => {
-            let mut statement = Vec::<BoatIns>::new();
-            let if_arg = translate_expr(expr, instruction_index, &mut statement, functions, labeled_lines);
-            *instruction_index += 1; // cmd and eq
-            let block = translate_block(block, instruction_index, functions, labeled_lines);
-            // *instruction_index += block.len() as u32;
-            
-            if else_block.is_some() {
-                *instruction_index += 1;
-            }
-            statement.push(BoatIns {cmd: BoatCmd::Cmp, args: vec![if_arg, BoatArg::Const(instruction_index.to_string())]});
-            labeled_lines.insert(*instruction_index);
-            statement.extend(block);
-
+        Statement::If { expr, block, else_block, .. } => {
+            let else_label = code.new_label();
+            translate_condition(expr, else_label, code, functions, target);
+            translate_block(block, code, functions, frame, target);
             if let Some(else_block) = else_block {
-                let else_block = translate_block(else_block, instruction_index, functions, labeled_lines);
-                statement.push(BoatIns { cmd: BoatCmd::Goto, args: vec![ BoatArg::Const(instruction_index.to_string()) ] });
-                labeled_lines.insert(*instruction_index);
-                // *instruction_index += else_block.len() as u32;
-                statement.extend(else_block);
+                let end_label = code.new_label();
+                code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(end_label)] }, span);
+                code.place(else_label);
+                translate_block(else_block, code, functions, frame, target);
+                code.place(end_label);
+            } else {
+                code.place(else_label);
+            }
+        }
+        Statement::While { expr, block, .. } => {
+            let begin_label = code.new_label();
+            let end_label = code.new_label();
+            code.place(begin_label);
+            translate_condition(expr, end_label, code, functions, target);
+            translate_loop_body(block, end_label, begin_label, code, functions, frame, target);
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(begin_label)] }, span);
+            code.place(end_label);
+        }
+        Statement::For { init, expr, step, block, .. } => {
+            let begin_label = code.new_label();
+            let step_label = code.new_label();
+            let end_label = code.new_label();
+            frame.scopes.push(vec![]);
+            for statement in init {
+                translate_statement(statement, code, functions, frame, target);
             }
-            statement
+            code.place(begin_label);
+            translate_condition(expr, end_label, code, functions, target);
+            translate_loop_body(block, end_label, step_label, code, functions, frame, target);
+            code.place(step_label);
+            for statement in step {
+                translate_statement(statement, code, functions, frame, target);
+            }
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(begin_label)] }, span);
+            code.place(end_label);
+            let vars = frame.scopes.pop().unwrap();
+            drop_vars(&vars, code);
+        }
+        Statement::Break(_) | Statement::Continue(_) => {
+            let current = frame.loops.last().unwrap();
+            let label = if matches!(s, Statement::Break(_)) { current.break_label } else { current.continue_label };
+            drop_scopes(&frame.scopes[current.scopes..], code);
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(label)] }, span);
         }
-        Statement::While { expr, block } => {
-            let mut statement = Vec::<BoatIns>::new();
-            let while_begin_index = *instruction_index;
-            let while_arg = translate_expr(expr, instruction_index, &mut statement, functions, labeled_lines);
-            *instruction_index += 1;
-            let block = translate_block(block, instruction_index, functions, labeled_lines);
-            statement.push(BoatIns { cmd: BoatCmd::Cmp, args: vec![while_arg, BoatArg::Const((*instruction_index + 1u32).to_string())] });
-            labeled_lines.insert(*instruction_index + 1u32);
-            statement.extend(block);
-            statement.push(BoatIns { cmd: BoatCmd::Goto, args: vec![ BoatArg::Const(while_begin_index.to_string()) ] });
-            labeled_lines.insert(while_begin_index);
-            *instruction_index += 1;
-            statement
+        Statement::Return(BoatExpr::Function { name, args, .. }, _) if frame.function.as_ref().is_some_and(|function| function.name == name) => {
+            translate_tail_call(args, frame, code, functions, target, span);
         }
-        Statement::Return(expr) => {
-            let mut instructions = Vec::<BoatIns>::new();
-            // let is_push_needed = matches!(expr, BoatExpr::Value(_) | BoatExpr::Var(_));
-            let arg = translate_expr(expr, instruction_index, &mut instructions, functions, labeled_lines);
+        Statement::Return(expr, _) => {
+            let arg = translate_expr(expr, code, functions, target);
             if arg != BoatArg::FromStack {
-                *instruction_index += 1;
-                instructions.push(BoatIns { cmd: BoatCmd::Push, args: vec![arg] });
+                code.push(BoatIns { cmd: BoatCmd::Push, args: vec![arg] }, span);
             }
-            instructions
+            // Variables are left for the program end
+            if frame.function.is_some() {
+                drop_scopes(&frame.scopes, code);
+            }
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(frame.epilogue)] }, span);
         }
         Statement::Expr(expr) => {
-            let mut instructions = Vec::<BoatIns>::new();
-            let _ = translate_expr(expr, instruction_index, &mut instructions, functions, labeled_lines);
-            instructions
+            let returns_value = match &expr {
+                BoatExpr::Function { name, .. } => functions.get(name).is_some_and(Function::returns_value),
+                _ => true,
+            };
+            // Unused result is popped to keep the stack balanced
+            if translate_expr(expr, code, functions, target) == BoatArg::FromStack && returns_value {
+                code.push(BoatIns::discard(), span);
+            }
         }
-        Statement::FunctionDefinition { name, arg_names, block } => {
-            let mut instructions = Vec::<BoatIns>::new();
-            
-            *instruction_index += 1;
-            functions.insert(name, Function::InProgram { begin_pos: *instruction_index, arg_names });
-            labeled_lines.insert(*instruction_index);
+        Statement::FunctionDefinition { name, arg_names, block, .. } => {
+            let skip_label = code.new_label();
+            let begin_label = code.new_label();
+            let epilogue_label = code.new_label();
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Label(skip_label)] }, span);
+            code.place(begin_label);
+            let returns_value = has_return(&block);
+            let falls_through = !matches!(block.last(), Some(Statement::Return(..)));
+            functions.insert(name.clone(), Function::InProgram { begin: begin_label, arg_names: arg_names.clone(), returns_value });
 
-            instructions.extend(translate_block(block, instruction_index, functions, labeled_lines));
-            instructions.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::FromKVS("return".to_string())] });
-            *instruction_index += 1;
-            instructions.insert(0, BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::Const(instruction_index.to_string())] });
-            labeled_lines.insert(*instruction_index);
-            instructions
+            let function = CurrentFunction { name, arg_names, begin: begin_label };
+            let mut function_frame = Frame { epilogue: epilogue_label, function: Some(function), scopes: vec![], loops: vec![] };
+            translate_block(block, code, functions, &mut function_frame, target);
+            let arg_names = function_frame.function.unwrap().arg_names;
+            // Function returning a value returns 0 when the body ends without `return`
+            if returns_value && falls_through {
+                code.push(BoatIns { cmd: BoatCmd::Push, args: vec![BoatArg::Const("0".to_owned())] }, span);
+            }
+            code.place(epilogue_label);
+            // Epilogue drops arguments of this call and returns to the caller
+            for name in arg_names.iter().rev() {
+                code.push(BoatIns { cmd: BoatCmd::KVDel, args: vec![BoatArg::Const(name.clone())] }, span);
+            }
+            code.push(BoatIns { cmd: BoatCmd::Goto, args: vec![BoatArg::FromKVS("return".to_string())] }, span);
+            code.place(skip_label);
         }
     }
 }
 
-pub fn translate_block(block: Block, instruction_index: &mut u32, functions: &mut Functions, labeled_lines: &mut HashSet<u32>) -> Vec<BoatIns> {
-    block.into_iter().flat_map(|statement| {
-        
-        // instruction_index += translated.len() as u32;
-        translate_statement(statement, instruction_index, functions, labeled_lines)
-    }).collect()
+// Variables declared in the block are dropped at its end
+fn translate_block(block: Block, code: &mut Code, functions: &mut Functions, frame: &mut Frame, target: &Target) {
+    frame.scopes.push(vec![]);
+    for statement in block {
+        translate_statement(statement, code, functions, frame, target);
+    }
+    let vars = frame.scopes.pop().unwrap();
+    drop_vars(&vars, code);
 }
 
-pub fn translate_program(program: Program, labeled_lines: &mut HashSet<u32>) -> Vec<BoatIns> {
+/// Translates the program into symbolic code for the target, to be laid out by `linker::link`
+pub fn translate_program(program: Program, target: &Target) -> Code {
     let Program { mut functions, block } = program;
-    translate_block(block, &mut 1, &mut functions, labeled_lines)
+    let mut code = Code::new();
+    // `return` outside of functions ends the program
+    let end_label = code.new_label();
+    // Variables of the program block live until the end, so they are not dropped
+    let mut frame = Frame { epilogue: end_label, function: None, scopes: vec![vec![]], loops: vec![] };
+    for statement in block {
+        translate_statement(statement, &mut code, &mut functions, &mut frame, target);
+    }
+    code.place(end_label);
+    code
 }
@@ -0,0 +1,58 @@
+use std::fmt::Display;
+
+pub const SCREEN_SIZE: usize = 7;
+
+/// Virtual 7x7 display painted by `di` and cleared by `dc`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Screen {
+    pixels: [[bool; SCREEN_SIZE]; SCREEN_SIZE],
+}
+
+impl Screen {
+    /// Paints pixel at column `x` and row `y`. Pixels outside of the display are ignored.
+    pub fn paint(&mut self, x: usize, y: usize) {
+        if x < SCREEN_SIZE && y < SCREEN_SIZE {
+            self.pixels[y][x] = true;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = Default::default();
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        x < SCREEN_SIZE && y < SCREEN_SIZE && self.pixels[y][x]
+    }
+
+    /// Row-major pixels, 1 for painted and 0 for clear
+    pub fn to_array(&self) -> [u8; SCREEN_SIZE * SCREEN_SIZE] {
+        let mut array = [0; SCREEN_SIZE * SCREEN_SIZE];
+        for (i, pixel) in self.pixels.iter().flatten().enumerate() {
+            array[i] = *pixel as u8;
+        }
+        array
+    }
+}
+
+impl Display for Screen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (y, row) in self.pixels.iter().enumerate() {
+            if y > 0 {
+                writeln!(f)?;
+            }
+            for pixel in row {
+                write!(f, "{}", if *pixel { '#' } else { '.' })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives the display every time the program changes it
+pub trait DisplayObserver {
+    fn update(&mut self, screen: &Screen);
+}
+
+impl DisplayObserver for () {
+    fn update(&mut self, _screen: &Screen) {}
+}
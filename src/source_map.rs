@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use crate::boat_program::Span;
+
+/// Source spans of compiled instructions, by 1-based instruction index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    spans: BTreeMap<u32, Span>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, index: u32, span: Span) {
+        self.spans.insert(index, span);
+    }
+
+    pub fn get(&self, index: u32) -> Option<Span> {
+        self.spans.get(&index).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Span)> + '_ {
+        self.spans.iter().map(|(i, span)| (*i, *span))
+    }
+}
+
+/// One `index line:col start..end` line per instruction
+impl Display for SourceMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, span) in self.iter() {
+            writeln!(f, "{i} {span} {}..{}", span.start, span.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SourceMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = SourceMap::new();
+        for (n, line) in s.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let error = || format!("invalid source map line {}: `{line}`", n + 1);
+            let mut parts = line.split_whitespace();
+            let mut next = || parts.next().ok_or_else(error);
+            let index = next()?.parse::<u32>().map_err(|_| error())?;
+            let (line_num, col) = next()?.split_once(':').ok_or_else(error)?;
+            let (start, end) = next()?.split_once("..").ok_or_else(error)?;
+            let parse = |s: &str| s.parse::<usize>().map_err(|_| error());
+            let span = Span { start: parse(start)?, end: parse(end)?, line: parse(line_num)?, col: parse(col)? };
+            map.insert(index, span);
+        }
+        Ok(map)
+    }
+}
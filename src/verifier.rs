@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+
+use crate::boat_instructions::{ArgError, BoatArg, BoatCmd, BoatIns};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VerifyErrorKind {
+    StackUnderflow { depth: usize, pops: usize },
+    InconsistentDepth { expected: isize, found: isize }, // Paths joining at the instruction have different stack depths
+    BadJumpTarget(String),
+    BadArgs(ArgError),
+    DynamicJump,                                          // Target is not a constant or a stored return address
+    InconsistentReturn { expected: isize, found: isize }, // Returns of a function leave different numbers of values
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub kind: VerifyErrorKind,
+    pub index: u32, // 1-based
+    pub ins: BoatIns,
+}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use VerifyErrorKind::*;
+        match self {
+            StackUnderflow { depth, pops } => write!(f, "pops {pops} values from stack of {depth}"),
+            InconsistentDepth { expected, found } => write!(f, "reached with stack depth {found}, but {expected} on another path"),
+            BadJumpTarget(s) => write!(f, "`{s}` is not a valid jump target"),
+            BadArgs(e) => write!(f, "{e}"),
+            DynamicJump => write!(f, "jump target is not known statically"),
+            InconsistentReturn { expected, found } => write!(f, "returns with {found} values, but with {expected} on another path"),
+        }
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}| {}: {}", self.index, self.ins, self.kind)
+    }
+}
+
+enum Target {
+    Index(usize), // 0-based, up to the program length
+    Return,       // Jump to an address stored by the caller
+    Invalid,
+    Dynamic,
+}
+
+struct Program<'a> {
+    instructions: &'a [BoatIns],
+    // Keys holding return addresses, read by jumps like `g $return`
+    address_keys: HashSet<String>,
+    // `g` right after `ka return N`, by index: called function start and return point
+    calls: HashMap<usize, (usize, usize)>,
+}
+
+impl Program<'_> {
+    fn resolve(&self, arg: &BoatArg) -> Target {
+        match arg {
+            BoatArg::Const(s) => match s.parse::<usize>() {
+                Ok(t) if (1..=self.instructions.len() + 1).contains(&t) => Target::Index(t - 1),
+                _ => Target::Invalid,
+            },
+            BoatArg::FromKVS(key) if self.address_keys.contains(key) => Target::Return,
+            _ => Target::Dynamic,
+        }
+    }
+}
+
+// Stack depths of a function body relative to its start, and the number of values it returns
+struct Walk {
+    depths: HashMap<usize, isize>,
+    returns: Option<isize>,
+    errors: Vec<(usize, VerifyErrorKind)>,
+}
+
+fn walk(program: &Program, entry: usize, effects: &HashMap<usize, isize>) -> Walk {
+    let instructions = program.instructions;
+    let mut walk = Walk { depths: HashMap::new(), returns: None, errors: Vec::new() };
+    let mut pending = VecDeque::from([(entry, 0isize)]);
+    while let Some((i, depth)) = pending.pop_front() {
+        if i >= instructions.len() {
+            continue;
+        }
+        match walk.depths.get(&i) {
+            Some(&expected) if expected != depth => {
+                walk.errors.push((i, VerifyErrorKind::InconsistentDepth { expected, found: depth }));
+                continue;
+            }
+            Some(_) => continue,
+            None => {
+                walk.depths.insert(i, depth);
+            }
+        }
+        let ins = &instructions[i];
+        let pops = ins.pops() as isize;
+        let mut depth = depth - pops;
+        if depth < 0 {
+            walk.errors.push((i, VerifyErrorKind::StackUnderflow { depth: (depth + pops) as usize, pops: pops as usize }));
+            depth = 0;
+        }
+        depth += ins.cmd.pushes() as isize;
+        // `clr s` and `st s` replace the stack, so it is not followed further
+        if matches!((&ins.cmd, ins.args.first()), (BoatCmd::Store | BoatCmd::Clear, Some(BoatArg::Const(s))) if s == "s") {
+            continue;
+        }
+        let Some(target) = ins.target() else {
+            pending.push_back((i + 1, depth));
+            continue;
+        };
+        if ins.cmd == BoatCmd::Cmp {
+            pending.push_back((i + 1, depth));
+        }
+        match program.resolve(target) {
+            Target::Index(t) => match program.calls.get(&i) {
+                // Return point is followed once the called function is walked
+                Some(&(function, return_point)) => {
+                    if let Some(effect) = effects.get(&function) {
+                        pending.push_back((return_point, depth + effect));
+                    }
+                }
+                None => pending.push_back((t, depth)),
+            },
+            Target::Return => match walk.returns {
+                Some(expected) if expected != depth => walk.errors.push((i, VerifyErrorKind::InconsistentReturn { expected, found: depth })),
+                _ => walk.returns = Some(depth),
+            },
+            Target::Invalid => walk.errors.push((i, VerifyErrorKind::BadJumpTarget(target.to_string()))),
+            Target::Dynamic => walk.errors.push((i, VerifyErrorKind::DynamicJump)),
+        }
+    }
+    walk
+}
+
+/// Checks argument counts and jump targets and that every instruction is reached with the same stack depth
+/// on all paths without popping an empty stack. Calls (`ka return N; g F`) are followed with the number of values
+/// the function leaves on the stack, found by walking the function until it jumps to the stored address.
+pub fn verify(instructions: &[BoatIns]) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::<(usize, VerifyErrorKind)>::new();
+    for (i, ins) in instructions.iter().enumerate() {
+        if let Err(e) = ins.check_args() {
+            errors.push((i, VerifyErrorKind::BadArgs(e)));
+        }
+    }
+    let address_keys = instructions.iter().filter_map(|ins| match ins.target() {
+        Some(BoatArg::FromKVS(key)) => Some(key.clone()),
+        _ => None,
+    }).collect::<HashSet<String>>();
+    let mut program = Program { instructions, address_keys, calls: HashMap::new() };
+    for (i, pair) in instructions.windows(2).enumerate() {
+        if let [BoatIns { cmd: BoatCmd::KVSet | BoatCmd::KVReSet, args: store }, BoatIns { cmd: BoatCmd::Goto, args: goto }] = pair {
+            if let ([BoatArg::Const(key), return_point], [function]) = (store.as_slice(), goto.as_slice()) {
+                if let (true, Target::Index(return_point), Target::Index(function)) = (program.address_keys.contains(key), program.resolve(return_point), program.resolve(function)) {
+                    program.calls.insert(i + 1, (function, return_point));
+                }
+            }
+        }
+    }
+    // Functions are walked until the number of values each one returns stops changing, as they can call each other
+    let functions = program.calls.values().map(|(function, _)| *function).collect::<HashSet<usize>>();
+    let mut effects = HashMap::<usize, isize>::new();
+    // Bounded, as a function returning more values on every recursive call never settles
+    for _ in 0..=functions.len() {
+        let mut changed = false;
+        for function in functions.iter() {
+            if let Some(returns) = walk(&program, *function, &effects).returns {
+                changed |= effects.insert(*function, returns) != Some(returns);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut reported = HashSet::<(usize, VerifyErrorKind)>::new();
+    for entry in std::iter::once(0).chain(functions) {
+        for error in walk(&program, entry, &effects).errors {
+            if reported.insert(error.clone()) {
+                errors.push(error);
+            }
+        }
+    }
+    errors.sort_by_key(|(i, _)| *i);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.into_iter().map(|(i, kind)| VerifyError { kind, index: i as u32 + 1, ins: instructions[i].clone() }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::instruction_parser::parse_instructions_unchecked;
+
+    #[test]
+    fn reports_every_wrong_argument_count() {
+        let instructions = parse_instructions_unchecked("o;p 1;kd;g 1;", &mut HashSet::new()).unwrap();
+        let errors = verify(&instructions).unwrap_err();
+        let bad_args = errors.iter().filter(|error| matches!(error.kind, VerifyErrorKind::BadArgs(_))).map(|error| error.index).collect::<Vec<u32>>();
+        assert_eq!(bad_args, [1, 3]);
+    }
+
+    fn errors(instructions: &str) -> Vec<(u32, VerifyErrorKind)> {
+        let instructions = parse_instructions_unchecked(instructions, &mut HashSet::new()).unwrap();
+        verify(&instructions).err().unwrap_or_default().into_iter().map(|error| (error.index, error.kind)).collect()
+    }
+
+    #[test]
+    fn stack_underflow() {
+        assert_eq!(errors("p 1;+ $ $;o 1 $;"), [(2, VerifyErrorKind::StackUnderflow { depth: 1, pops: 2 })]);
+    }
+
+    #[test]
+    fn different_depths_where_paths_join() {
+        assert_eq!(errors("i 1;c $ 4;p 1;o 1 done;"), [(4, VerifyErrorKind::InconsistentDepth { expected: 0, found: 1 })]);
+    }
+
+    #[test]
+    fn jump_targets_out_of_range() {
+        assert_eq!(errors("p 1;c $ 0;g 5;"), [(2, VerifyErrorKind::BadJumpTarget("0".into())), (3, VerifyErrorKind::BadJumpTarget("5".into()))]);
+        // One past the last instruction ends the program
+        assert_eq!(errors("g 2;"), []);
+    }
+
+    // `ka return N; g F` continues at N with the values the function leaves on the stack
+    #[test]
+    fn calls_return_with_values_of_the_function() {
+        assert_eq!(errors("g 4;p 7;g $return;ka return 6;g 2;kd return;o 1 $;"), []);
+        assert_eq!(errors("g 3;g $return;ka return 5;g 2;kd return;o 1 $;"), [(6, VerifyErrorKind::StackUnderflow { depth: 0, pops: 1 })]);
+        let different_returns = "g 7;i 1;c $ 6;p 1;g $return;g $return;ka return 9;g 2;kd return;";
+        assert_eq!(errors(different_returns), [(5, VerifyErrorKind::InconsistentReturn { expected: 0, found: 1 })]);
+    }
+}
@@ -0,0 +1,44 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use boat_lang_core::{
+    boat_instructions::{self, Target},
+    compiler,
+    instruction_parser,
+    source_map::SourceMap,
+};
+
+// Compiled programs printed by every formatter parse back to the same instructions
+#[test]
+fn compiled_programs_parse_back() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+    let mut paths = fs::read_dir(programs).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+        if path.file_name().unwrap() == "parsing_error.boat" {
+            continue;
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        for optimize in [false, true] {
+            let mut labeled_lines = HashSet::new();
+            let target = Target { extended_math: true };
+            let translated = compiler::compile(&contents, &target, optimize, &mut labeled_lines, &mut SourceMap::new())
+                .unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+            let printed = [
+                boat_instructions::translated_to_string(translated.clone()),
+                boat_instructions::translated_to_string2(translated.clone(), false, &labeled_lines),
+                boat_instructions::translated_to_string2(translated.clone(), true, &labeled_lines),
+            ];
+            for printed in printed {
+                let parsed = instruction_parser::parse_instructions(&printed, &mut HashSet::new())
+                    .unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+                assert_eq!(parsed, translated, "{}:\n{printed}", path.display());
+            }
+        }
+    }
+}
+
+#[test]
+fn constants_with_spaces_and_empty_constants() {
+    let parsed = instruction_parser::parse_instructions("= $value ;o 1 a >= b;ka found ;4|o 1 x;", &mut HashSet::new()).unwrap();
+    assert_eq!(boat_instructions::translated_to_string(parsed), "= $value ;o 1 a >= b;ka found ;o 1 x;");
+}
@@ -0,0 +1,293 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use boat_lang_core::{
+    boat_instructions::{self, Target, DISCARD_KEY},
+    clock::SimulatedClock,
+    compiler,
+    interpreter,
+    pin_bus::{RecordingBus, ScriptedBus},
+    screen::{DisplayObserver, Screen},
+    source_map::SourceMap,
+    verifier,
+};
+
+// Values written to pin 1 and variables left at the end, the same with and without the peephole optimizer
+fn run_with_kvs(source: &str, inputs: &[&str]) -> (Vec<String>, Vec<String>) {
+    let results = [false, true].map(|optimize| {
+        let mut source_map = SourceMap::new();
+        let program = compiler::compile(source, &Target::default(), optimize, &mut HashSet::new(), &mut source_map).unwrap();
+        verifier::verify(&program).unwrap();
+        let mut bus = RecordingBus::new(ScriptedBus::new().with_inputs("2", inputs.iter().copied()));
+        let kvs = interpreter::interpret(&program, &mut bus, &mut (), &mut SimulatedClock::new(), Some(&source_map), None).unwrap();
+        let outputs = bus.outputs("1").into_iter().map(|(_, value)| value.to_owned()).collect::<Vec<String>>();
+        // Only variables of the program body are left for its end, each with a single value
+        let mut left = kvs.into_iter().filter(|(key, values)| key != DISCARD_KEY && !values.is_empty()).map(|(key, values)| {
+            assert_eq!(values.len(), 1, "`{key}` has values of several scopes: {values:?}");
+            key
+        }).collect::<Vec<String>>();
+        left.sort();
+        (outputs, left)
+    });
+    let [plain, optimized] = results;
+    assert_eq!(plain, optimized);
+    plain
+}
+
+fn run(source: &str, inputs: &[&str]) -> Vec<String> {
+    run_with_kvs(source, inputs).0
+}
+
+fn program(name: &str) -> String {
+    fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("programs").join(name)).unwrap()
+}
+
+#[test]
+fn early_return() {
+    assert_eq!(run(&program("early_return.boat"), &[]), ["5", "7", "-101"]);
+}
+
+#[test]
+fn int_sqrt() {
+    let source = program("early_return.boat").replace("print(int_sqrt(35));", "print(int_sqrt(in(2)));");
+    assert_eq!(run(&source, &["0"])[0], "0");
+    assert_eq!(run(&source, &["1"])[0], "1");
+    assert_eq!(run(&source, &["99"])[0], "9");
+    assert_eq!(run(&source, &["100"])[0], "10");
+}
+
+#[test]
+fn sign() {
+    let source = "print = out(1); input = in(2); {
+        function sign(x) {
+            if (x < 0) {
+                return -1;
+            }
+            if (x > 0) {
+                return 1;
+            }
+            return 0;
+        }
+        print(sign(input()));
+        print(sign(input()));
+        print(sign(input()));
+    }";
+    assert_eq!(run(source, &["-0.5", "0", "3"]), ["-1", "0", "1"]);
+}
+
+// Locals of loop bodies are dropped by `return`, so callers see their own variables of the same names
+#[test]
+fn return_inside_loops_drops_lets() {
+    let source = "print = out(1); input = in(2); {
+        function find_while(n) {
+            let x = 0;
+            while (1) {
+                let y = x * x;
+                if (y >= n) {
+                    return x;
+                }
+                x = x + 1;
+            }
+        }
+        function find_for(n) {
+            for (let x = 0; 1; x = x + 1) {
+                let y = x * 3;
+                if (y > n) {
+                    let z = y - n;
+                    return z;
+                }
+            }
+        }
+        let x = \"x\";
+        let y = \"y\";
+        let z = \"z\";
+        print(find_while(input()));
+        print(x .. y .. z);
+        print(find_for(input()));
+        print(x .. y .. z);
+        print(find_while(10) + find_for(10));
+        print(x .. y .. z);
+    }";
+    assert_eq!(run(source, &["17", "7"]), ["5", "xyz", "2", "xyz", "6", "xyz"]);
+}
+
+// Arguments are read before the body of the function runs, inlined or not
+#[test]
+fn inlined_arguments_are_read_before_calls_of_the_body() {
+    let source = "print = out(1); {
+        let v = 1;
+        function bump() {
+            v = v + 10;
+            return 0;
+        }
+        HINT function f(x) {
+            return bump() + x;
+        }
+        print(f(v));
+        print(f(2));
+    }";
+    let inlined = run(&source.replace("HINT ", ""), &[]);
+    assert_eq!(inlined, run(&source.replace("HINT", "noinline"), &[]));
+    assert_eq!(inlined, ["1", "2"]);
+}
+
+#[test]
+fn function_with_only_dead_returns_returns_zero() {
+    let source = "print = out(1); {
+        function f() {
+            if (0) {
+                return 1;
+            }
+        }
+        function g() {
+            while (0) {
+                return 2;
+            }
+            print(\"g\");
+        }
+        print(f());
+        print(g());
+    }";
+    assert_eq!(run(source, &[]), ["0", "g", "0"]);
+}
+
+// `-` binds looser than `**` for literals and variables alike
+#[test]
+fn unary_minus_binds_looser_than_power() {
+    let source = "print = out(1); input = in(2); {
+        let x = input();
+        print(-2 ** 2);
+        print(-x ** 2);
+        print((-2) ** 2);
+        print((-x) ** 2);
+        print(2 ** -1 * 4);
+        print(3 - -x);
+    }";
+    assert_eq!(run(source, &["2"]), ["-4", "-4", "4", "4", "2", "5"]);
+}
+
+// Only variables named like a parameter bound before them are read through the stack
+#[test]
+fn call_arguments_are_read_before_parameters_change() {
+    let source = "print = out(1); {
+        function f(a, b) {
+            print(a .. b);
+            return 0;
+        }
+        let a = \"A\";
+        let b = \"B\";
+        let x = \"X\";
+        f(b, a);
+        f(a, b);
+        f(x, b);
+    }";
+    assert_eq!(run(source, &[]), ["BA", "AB", "XB"]);
+    let program = compiler::compile(source, &Target::default(), false, &mut HashSet::new(), &mut SourceMap::new()).unwrap();
+    let text = boat_instructions::translated_to_string(program);
+    assert!(text.contains("p $a;ka a $b;ka b $;"), "{text}");
+    assert!(text.contains("ka a $a;ka b $b;"), "{text}");
+    assert!(text.contains("ka a $x;ka b $b;"), "{text}");
+}
+
+// Right operands of `&&` and `||` run only when the left one does not decide the result
+#[test]
+fn logical_operators_skip_right_operand() {
+    let source = "print = out(1); input = in(2); {
+        function side(value) {
+            print(\"side\");
+            return value;
+        }
+        print(0 && input());
+        print(1 || input());
+        print(0 && side(1));
+        print(1 || side(0));
+        print(input());
+        print(1 && input());
+        print(0 || side(0));
+    }";
+    assert_eq!(run(source, &["first", "1"]), ["0", "1", "0", "1", "first", "1", "side", "0"]);
+}
+
+// Variables of loop bodies and `for` clauses are dropped by `break` and `continue` too
+#[test]
+fn loops() {
+    let (outputs, left) = run_with_kvs(&program("loops.boat"), &[]);
+    assert_eq!(outputs, ["27", "8", "12 21 ", "5"]);
+    assert_eq!(left, ["found", "n", "sum"]);
+}
+
+// Tail calls reuse the arguments of the call, so variables never hold values of several calls
+#[test]
+fn tail_calls_keep_kvs_size() {
+    let source = "print = out(1); {
+        function sum(n, acc) {
+            if (n < 1) {
+                return acc;
+            }
+            let step = n;
+            return sum(n - 1, acc + step);
+        }
+        print(sum(500, 0));
+    }";
+    assert_eq!(run_with_kvs(source, &[]), (vec!["125250".to_owned()], vec![]));
+    let program = compiler::compile(source, &Target::default(), true, &mut HashSet::new(), &mut SourceMap::new()).unwrap();
+    let mut trace = Vec::<u8>::new();
+    interpreter::interpret(&program, &mut ScriptedBus::new(), &mut (), &mut SimulatedClock::new(), None, Some(&mut trace)).unwrap();
+    let trace = String::from_utf8(trace).unwrap();
+    // Lines are `N| instruction -- stack -- kvs`
+    let kvs = trace.lines().map(|line| line.rsplit(" -- ").next().unwrap()).collect::<Vec<&str>>();
+    assert!(kvs.len() > 500 * 5);
+    for key in ["n", "acc", "step", "return"] {
+        let most = kvs.iter().filter_map(|kvs| kvs.split(&format!("\"{key}\": [")).nth(1)).map(|values| {
+            values.split(']').next().unwrap().matches(", ").count() + 1
+        }).max();
+        assert_eq!(most, Some(1), "`{key}` holds values of several calls");
+    }
+}
+
+// `let` in a loop body declares a new variable on every iteration and drops it at the end of the body
+#[test]
+fn loop_body_variables_are_dropped_every_iteration() {
+    let source = "print = out(1); {
+        let i = 0;
+        let total = 0;
+        while (i < 50) {
+            let square = i * i;
+            let half = square / 2;
+            total = total + half;
+            i = i + 1;
+        }
+        print(total);
+        print(i);
+    }";
+    assert_eq!(run_with_kvs(source, &[]), (vec!["20212.5".to_owned(), "50".to_owned()], vec!["i".to_owned(), "total".to_owned()]));
+}
+
+// Screens shown after every change of the display
+#[derive(Default)]
+struct DisplayRecorder(Vec<String>);
+
+impl DisplayObserver for DisplayRecorder {
+    fn update(&mut self, screen: &Screen) {
+        self.0.push(screen.to_string());
+    }
+}
+
+#[test]
+fn display() {
+    let source = "{
+        display(0, 0);
+        display(6, 1);
+        display(7, 0);
+        dclear();
+        display(3, 6);
+    }";
+    let program = compiler::compile(source, &Target::default(), false, &mut HashSet::new(), &mut SourceMap::new()).unwrap();
+    let mut display = DisplayRecorder::default();
+    interpreter::interpret(&program, &mut ScriptedBus::new(), &mut display, &mut SimulatedClock::new(), None, None).unwrap();
+    let first = ["#......", ".......", ".......", ".......", ".......", ".......", "......."].join("\n");
+    // Pixels outside of the display are ignored, but the display is still updated
+    let second = ["#......", "......#", ".......", ".......", ".......", ".......", "......."].join("\n");
+    let cleared = [".......", ".......", ".......", ".......", ".......", ".......", "......."].join("\n");
+    let last = [".......", ".......", ".......", ".......", ".......", ".......", "...#..."].join("\n");
+    assert_eq!(display.0, [first, second.clone(), second, cleared, last]);
+}
@@ -1,15 +1,17 @@
 extern crate boat_lang_core;
 
 use crate::boat_lang_core::{
-    program_parser,
-    program_translator,
-    program_optimizer,
+    compiler,
     interpreter,
-    boat_instructions,
+    boat_instructions::{self, Target},
+    screen::{DisplayObserver, Screen},
+    pin_bus::{LineSource, StdioBus},
+    clock::{Clock, SimulatedClock},
+    source_map::SourceMap,
 };
 extern crate wasm_bindgen;
 use wasm_bindgen::prelude::*;
-use std::{collections::HashSet, io::{BufRead, Read}};
+use std::{cell::RefCell, collections::HashSet, io::Write, rc::Rc, time::Duration};
 
 #[wasm_bindgen]
 extern "C" {
@@ -17,63 +19,59 @@ extern "C" {
     fn prompt() -> Option<String>;
 }
 
-struct JSReader {
-    buf: String
-}
+/// Reads inputs with the browser prompt. Cancelling the prompt times out `ia`
+struct JSReader;
 
-impl JSReader {
-    fn new() -> JSReader {
-        JSReader {
-            buf: String::new()
+impl LineSource for JSReader {
+    fn next_line(&mut self, timeout: Option<Duration>, _clock: &mut dyn Clock) -> std::io::Result<Option<String>> {
+        match prompt() {
+            Some(s) => Ok(Some(s.trim().to_string())),
+            None if timeout.is_some() => Ok(None),
+            None => Ok(Some(String::new())),
         }
     }
 }
 
-impl Read for JSReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let s = prompt();
-        if s.is_none() {
-            self.buf.clear();
-            return Ok(0);
-        }
-        self.buf = s.unwrap();
-        self.buf.push('\n');
-        let n = std::cmp::min(buf.len(), self.buf.len());
-        buf[..n].copy_from_slice(&self.buf.as_bytes()[..n]);
-        Ok(n)
+/// Output buffer shared by the pins and the debug trace to keep their lines in order
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
-impl BufRead for JSReader {
-    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
-        if self.buf.is_empty() {
-            let s = prompt();
-            if s.is_none() {
-                return Ok(&[]);
-            }
-            self.buf = s.unwrap();
-            self.buf.push('\n');
-        }
-        Ok(self.buf.as_bytes())
-    }
-    fn consume(&mut self, amt: usize) {
-        self.buf.drain(..amt);
-        // self.buf = self.buf[amt..].to_string();
+thread_local! {
+    static FRAMES: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records every display frame as 49 row-major bytes for drawing on a canvas
+struct FrameRecorder;
+
+impl DisplayObserver for FrameRecorder {
+    fn update(&mut self, screen: &Screen) {
+        FRAMES.with_borrow_mut(|frames| frames.extend(screen.to_array()));
     }
 }
 
+/// Display frames of the last `boat_lang_interpret` run, 49 bytes per frame
 #[wasm_bindgen]
-pub fn boat_lang_compile(contents: String, legacy: bool, preety: bool) -> String {
-    let mut program = match program_parser::parse_program(&contents) {
-        Ok(program) => program,
-        Err(e) => {
-            // println!("{}", e);
-            return format!("{e}");
-        }
-    };
-    program_optimizer:: optimize_reassigns(&mut program);
+pub fn boat_lang_display_frames() -> Vec<u8> {
+    FRAMES.with_borrow(|frames| frames.clone())
+}
+
+#[wasm_bindgen]
+pub fn boat_lang_compile(contents: String, legacy: bool, preety: bool, optimize: bool, extended_math: bool) -> String {
+    let target = Target { extended_math };
     let mut labeled_lines = HashSet::<u32>::new();
-    let translated = program_translator::translate_program(program, &mut labeled_lines);
+    let translated = match compiler::compile(&contents, &target, optimize, &mut labeled_lines, &mut SourceMap::new()) {
+        Ok(translated) => translated,
+        Err(e) => return format!("{e}\n"),
+    };
     if legacy {
         boat_instructions::translated_to_string(translated)
     } else {
@@ -82,18 +80,24 @@ pub fn boat_lang_compile(contents: String, legacy: bool, preety: bool) -> String
 }
 
 #[wasm_bindgen]
-pub fn boat_lang_interpret(contents: String, debug: bool) -> String {
-    let mut program = match program_parser::parse_program(&contents) {
-        Ok(program) => program,
-        Err(e) => {
-            // println!("{}", e);
-            return format!("{e}");
-        }
-    };
-    program_optimizer::optimize_reassigns(&mut program);
+pub fn boat_lang_interpret(contents: String, debug: bool, optimize: bool, extended_math: bool) -> String {
+    let target = Target { extended_math };
     let mut labeled_lines = HashSet::<u32>::new();
-    let translated = program_translator::translate_program(program, &mut labeled_lines);
-    let mut out = Vec::<u8>::new();
-    interpreter::interpret(&translated, &mut out, JSReader::new(), debug);
-    String::from_utf8(out).expect("output is valid utf8")
+    let mut source_map = SourceMap::new();
+    let translated = match compiler::compile(&contents, &target, optimize, &mut labeled_lines, &mut source_map) {
+        Ok(translated) => translated,
+        Err(e) => return format!("{e}\n"),
+    };
+    let out = SharedBuffer::default();
+    let mut bus = StdioBus::new(JSReader, out.clone());
+    let mut trace = out.clone();
+    FRAMES.with_borrow_mut(|frames| frames.clear());
+    // Browser can not block on sleep, so time is only simulated
+    let mut clock = SimulatedClock::new();
+    let result = interpreter::interpret(&translated, &mut bus, &mut FrameRecorder, &mut clock, Some(&source_map), debug.then_some(&mut trace as &mut dyn Write));
+    let mut text = String::from_utf8_lossy(&out.0.borrow()).into_owned();
+    if let Err(e) = result {
+        text.push_str(&format!("{e}\n"));
+    }
+    text
 }